@@ -0,0 +1,129 @@
+//! Buffers owned by a transfer rather than borrowed, in the style of `embedded-dma`. Because the
+//! words of such a buffer are never freed while the buffer exists, dropping or even leaking the
+//! future of a transfer can not leave the interrupt handler or DMA pointing to freed memory.
+//!
+//! Both are implemented for any `StableDeref` pointer to a slice or array of words that lives for
+//! `'static`, like `&'static mut [u8; N]` or a box from a memory pool.
+
+use core::ops::{Deref, DerefMut};
+
+pub use stable_deref_trait::StableDeref;
+
+use crate::Word;
+
+/// A buffer whose words are written to the bus.
+///
+/// # Safety
+///
+/// `read_buffer` must return the same pointer and length every time it is called, and the words
+/// must stay valid for reads for as long as the buffer is not dropped, even when the buffer is
+/// moved or leaked.
+pub unsafe trait ReadBuffer {
+    type Word: Word;
+
+    /// The pointer to the words and the number of words.
+    ///
+    /// # Safety
+    ///
+    /// The words must not be accessed after the buffer is dropped.
+    unsafe fn read_buffer(&self) -> (*const Self::Word, usize);
+}
+
+/// A buffer that the words read from the bus are stored in.
+///
+/// # Safety
+///
+/// `write_buffer` must return the same pointer and length every time it is called, and the words
+/// must stay valid for reads and writes for as long as the buffer is not dropped, even when the
+/// buffer is moved or leaked.
+pub unsafe trait WriteBuffer {
+    type Word: Word;
+
+    /// The pointer to the words and the number of words.
+    ///
+    /// # Safety
+    ///
+    /// The words must not be accessed after the buffer is dropped, nor through any other
+    /// reference to the buffer.
+    unsafe fn write_buffer(&mut self) -> (*mut Self::Word, usize);
+}
+
+/// The words a `ReadBuffer` points to.
+///
+/// # Safety
+///
+/// `as_read_buffer` must return the pointer to the words of `self` and the number of words.
+pub unsafe trait ReadTarget {
+    type Word: Word;
+
+    fn as_read_buffer(&self) -> (*const Self::Word, usize);
+}
+
+/// The words a `WriteBuffer` points to.
+///
+/// # Safety
+///
+/// `as_write_buffer` must return the pointer to the words of `self` and the number of words.
+pub unsafe trait WriteTarget {
+    type Word: Word;
+
+    fn as_write_buffer(&mut self) -> (*mut Self::Word, usize);
+}
+
+unsafe impl<W: Word> ReadTarget for [W] {
+    type Word = W;
+
+    fn as_read_buffer(&self) -> (*const W, usize) {
+        (self.as_ptr(), self.len())
+    }
+}
+
+unsafe impl<W: Word, const N: usize> ReadTarget for [W; N] {
+    type Word = W;
+
+    fn as_read_buffer(&self) -> (*const W, usize) {
+        (self.as_ptr(), N)
+    }
+}
+
+unsafe impl<W: Word> WriteTarget for [W] {
+    type Word = W;
+
+    fn as_write_buffer(&mut self) -> (*mut W, usize) {
+        (self.as_mut_ptr(), self.len())
+    }
+}
+
+unsafe impl<W: Word, const N: usize> WriteTarget for [W; N] {
+    type Word = W;
+
+    fn as_write_buffer(&mut self) -> (*mut W, usize) {
+        (self.as_mut_ptr(), N)
+    }
+}
+
+// A `StableDeref` pointer keeps pointing to the same words when it is moved, and because it lives
+// for `'static` the words are not freed before it is dropped.
+unsafe impl<B, T> ReadBuffer for B
+where
+    B: Deref<Target = T> + StableDeref + 'static,
+    T: ReadTarget + ?Sized,
+{
+    type Word = T::Word;
+
+    unsafe fn read_buffer(&self) -> (*const T::Word, usize) {
+        (**self).as_read_buffer()
+    }
+}
+
+unsafe impl<B, T> WriteBuffer for B
+where
+    B: DerefMut<Target = T> + StableDeref + 'static,
+    T: WriteTarget + ?Sized,
+{
+    type Word = T::Word;
+
+    unsafe fn write_buffer(&mut self) -> (*mut T::Word, usize) {
+        (**self).as_write_buffer()
+    }
+}
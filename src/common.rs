@@ -1,9 +1,16 @@
 use core::cell::UnsafeCell;
-use core::mem::MaybeUninit;
-use core::ptr::null_mut;
+use core::future::{poll_fn, Future};
+use core::mem::{align_of, replace, size_of, size_of_val, MaybeUninit};
+use core::pin::pin;
+use core::ptr::{null, null_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
 
 use async_heapless::Oneshot;
 
+use crate::crc::{self, Checksum};
+use crate::{ReadBuffer, WriteBuffer};
+
 // Hardware management of NSS is not sufficient: It drives the pin low when SPE is enabled but does
 // not drive the pin high when it is disabled, so it ends up floating low.
 
@@ -14,6 +21,101 @@ pub enum Error {
     ModeFault,
     BadChecksum,
     Uninitialized,
+    /// A DMA transfer failed because of a bus error.
+    Dma,
+    /// The transfer did not complete in time.
+    Timeout,
+    /// The hardware can not perform the operation, like an operation that both writes and reads
+    /// on a half-duplex data line, or apply the configuration.
+    Unsupported,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    /// The clock is low when idle.
+    IdleLow,
+    /// The clock is high when idle.
+    IdleHigh,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Data is captured on the first clock edge.
+    CaptureOnFirstTransition,
+    /// Data is captured on the second clock edge.
+    CaptureOnSecondTransition,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mode {
+    pub polarity: Polarity,
+    pub phase: Phase,
+}
+
+pub const MODE_0: Mode = Mode {
+    polarity: Polarity::IdleLow,
+    phase: Phase::CaptureOnFirstTransition,
+};
+pub const MODE_1: Mode = Mode {
+    polarity: Polarity::IdleLow,
+    phase: Phase::CaptureOnSecondTransition,
+};
+pub const MODE_2: Mode = Mode {
+    polarity: Polarity::IdleHigh,
+    phase: Phase::CaptureOnFirstTransition,
+};
+pub const MODE_3: Mode = Mode {
+    polarity: Polarity::IdleHigh,
+    phase: Phase::CaptureOnSecondTransition,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// Motorola frames, the usual SPI format.
+    Motorola,
+    /// Motorola frames with a pulse on NSS between consecutive frames, which needs NSS to be
+    /// driven by the peripheral and the phase to be `CaptureOnFirstTransition`.
+    MotorolaPulsed,
+    /// TI synchronous serial frames, where a pulse on NSS precedes every frame. This needs NSS to
+    /// be driven by the peripheral as master. The mode and bit order are fixed by the format, so
+    /// those of the configuration are ignored. Framing errors as slave fail the transfer with
+    /// `Error::BadFrameFormat`.
+    TI,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub mode: Mode,
+    pub bit_order: BitOrder,
+    pub frame_format: FrameFormat,
+    /// The requested clock frequency in Hz.
+    pub frequency: u32,
+}
+
+impl Config {
+    /// A configuration sending Motorola frames with the most significant bit first.
+    pub const fn new(mode: Mode, frequency: u32) -> Self {
+        Self {
+            mode,
+            bit_order: BitOrder::MsbFirst,
+            frame_format: FrameFormat::Motorola,
+            frequency,
+        }
+    }
+
+    /// A configuration sending TI synchronous serial frames.
+    pub const fn ti(frequency: u32) -> Self {
+        Self {
+            frame_format: FrameFormat::TI,
+            ..Self::new(MODE_1, frequency)
+        }
+    }
 }
 
 pub trait SPIHardware {
@@ -23,25 +125,629 @@ pub trait SPIHardware {
     fn read(&self) -> Result<Option<u8>, Error>;
     /// Write a data byte to the SPI peripheral.
     fn write(&self, x: u8);
+    /// Like `read`, but for frames of more than 8 bits.
+    fn read_u16(&self) -> Result<Option<u16>, Error>;
+    /// Like `write`, but for frames of more than 8 bits.
+    fn write_u16(&self, x: u16);
+    /// Set the number of bits per frame, from 4 to 16. This is only called while no transfer is in
+    /// progress.
+    fn set_frame_size(&mut self, bits: u8);
+    /// Apply the configuration and return the clock frequency achieved, which should be as close
+    /// to the requested frequency as possible without exceeding it. Return
+    /// `Err(Error::Unsupported)` without changing anything if the hardware can not apply it, like
+    /// when even its slowest clock exceeds the requested frequency. This is only called while no
+    /// transfer is in progress.
+    fn configure(&mut self, config: &Config) -> Result<u32, Error>;
+    /// Start moving a block of words using DMA, and return the number of words in the block that
+    /// will be moved, which may be fewer than `block.len`. When they have been moved or an error
+    /// occurs, an interrupt should trigger the interrupt handler, which will then call `poll_dma`.
+    /// Return 0 if DMA is not supported, in which case the words are moved one at a time using
+    /// `read` and `write`.
+    fn start_dma(&mut self, _block: &DmaBlock) -> usize {
+        0
+    }
+    /// Return `Ok(true)` if the block started by `start_dma` has been moved, `Ok(false)` if it is
+    /// still in progress. This method gets called from the interrupt handler and must always clear
+    /// the cause of the interrupt. After an error or a completed block, the hardware must be ready
+    /// to move words one at a time again.
+    fn poll_dma(&mut self) -> Result<bool, Error> {
+        unreachable!()
+    }
+    /// Stop the transfer in progress, waiting for or discarding the frame or DMA block that is
+    /// being moved. This is called either from outside the interrupt handler, which may run in the
+    /// middle of it, or from `SPIHandler::handle_deselect` at the priority of the interrupt
+    /// handler, which then does not run until it returns. Once this returns, the interrupt handler
+    /// must not be triggered again until the next `write` or `start_dma`.
+    fn abort(&mut self);
+    /// Clear the cause of an error returned by `read` or `poll_dma` and bring the peripheral back
+    /// in a state where it can start a new transfer, discarding any frames still queued for
+    /// transmission. This method gets called from the interrupt handler. As slave, the interrupt
+    /// handler must not be triggered again until the next `write`, even if the master keeps
+    /// clocking frames.
+    fn recover(&mut self);
+    /// The number of frames the receive fifo can hold, which is how many frames the interrupt
+    /// handler keeps in flight: written but not yet read. Every time the interrupt handler is
+    /// called, it reads until `read` returns `Ok(None)` and then writes until this many frames are
+    /// in flight again. Defaults to 1, which writes a frame only after the previous one was read.
+    fn fifo_depth(&self, _wide: bool) -> usize {
+        1
+    }
+    /// Called right after the last word of a transfer has been written, when the words are not
+    /// moved by DMA. If the hardware appends a checksum, make it send the checksum next and return
+    /// the number of frames it takes, which are read and discarded. Defaults to 0.
+    fn crc_next(&mut self) -> usize {
+        0
+    }
+    /// Called when a transfer is complete, including when moved by DMA. Return
+    /// `Err(Error::BadChecksum)` if the checksum received did not match, and get ready to
+    /// calculate the checksum of the next transfer.
+    fn check_crc(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Whether the data is moved over a single, bidirectional line, which the interrupt handler
+    /// turns around with `set_direction`. Operations that both write and read then fail with
+    /// `Error::Unsupported`. Defaults to `false`.
+    fn half_duplex(&self) -> bool {
+        false
+    }
+    /// Switch the data line of half-duplex hardware to output when `output` is set, or else to
+    /// input, and return whether it was turned around. This is called by the interrupt handler
+    /// before every operation, once every frame written before has been read.
+    fn set_direction(&mut self, _output: bool) -> bool {
+        false
+    }
+    /// Called when a transfer is complete, before the result is reported. If `read` returns frames
+    /// before they have been sent, like on a half-duplex data line, wait until the last frame has
+    /// been sent. Defaults to doing nothing.
+    fn flush(&mut self) {}
+    /// Enable the peripheral and its interrupts, called by `SPIHandler::init`. This undoes
+    /// `disable` when released hardware is given to `init` again.
+    fn enable(&mut self) {}
+    /// Disable the peripheral and its interrupts, called by `SPI::release`. No transfer is in
+    /// progress.
+    fn disable(&mut self) {}
 }
 
-pub struct SPI<H: 'static> {
+/// A block of words to be moved by DMA. For every word, `tx` is written and `rx` is read, each of
+/// them pointing to the next word after every frame if the corresponding increment flag is set.
+#[derive(Clone, Copy, Debug)]
+pub struct DmaBlock {
+    pub tx: *const u8,
+    pub tx_increment: bool,
+    pub rx: *mut u8,
+    pub rx_increment: bool,
+    pub len: usize,
+    /// Whether the words are `u16` rather than `u8`.
+    pub wide: bool,
+}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+}
+
+/// The type holding a single frame: `u8` for frames of 4 to 8 bits and `u16` for frames of 9 to
+/// 16 bits.
+pub trait Word: Copy + 'static + private::Sealed {
+    const MIN_BITS: u8;
+    const MAX_BITS: u8;
+    const ZERO: Self;
+    fn into_u16(self) -> u16;
+    /// Truncate to the size of the word.
+    fn from_u16(x: u16) -> Self;
+}
+
+impl Word for u8 {
+    const MIN_BITS: u8 = 4;
+    const MAX_BITS: u8 = 8;
+    const ZERO: Self = 0;
+
+    fn into_u16(self) -> u16 {
+        u16::from(self)
+    }
+
+    fn from_u16(x: u16) -> Self {
+        x as u8
+    }
+}
+
+impl Word for u16 {
+    const MIN_BITS: u8 = 9;
+    const MAX_BITS: u8 = 16;
+    const ZERO: Self = 0;
+
+    fn into_u16(self) -> u16 {
+        self
+    }
+
+    fn from_u16(x: u16) -> Self {
+        x
+    }
+}
+
+pub struct SPI<H: 'static, W: Word = u8> {
     handler: &'static SPIHandler<H>,
+    /// The word written while reading after the words to write have run out.
+    fill: W,
+    /// The number of frames discarded after turning the data line around to read.
+    turnaround: usize,
+}
+
+/// A single step of a `SPI::transaction`.
+pub enum Operation<'a, W: Word = u8> {
+    /// Read into the buffer while writing the fill word.
+    Read(&'a mut [W]),
+    /// Write the buffer, discarding the words read.
+    Write(&'a [W]),
+    /// Write the second buffer while reading into the first, like `SPI::transfer`.
+    Transfer(&'a mut [W], &'a [W]),
+    /// Write the buffer and replace its contents with the words read.
+    TransferInPlace(&'a mut [W]),
+    /// Wait for the given number of nanoseconds.
+    DelayNs(u32),
+    /// Write the buffer followed by its checksum, which is calculated after resetting the
+    /// `Checksum`. With words of 16 bits, a checksum of one byte fails with `Error::Unsupported`.
+    WriteWithCrc(&'a [W], &'a mut dyn Checksum),
+    /// Read into the buffer followed by a checksum, failing with `Error::BadChecksum` if it does
+    /// not match the checksum of the buffer, which is calculated after resetting the `Checksum`.
+    ReadWithCrc(&'a mut [W], &'a mut dyn Checksum),
+}
+
+/// The error returned by `SPI::transaction`.
+#[derive(Clone, Copy, Debug)]
+pub struct OperationError {
+    /// The index of the operation that failed.
+    pub index: usize,
+    pub error: Error,
+}
+
+/// The error returned by transfers, which also tells how far the transfer got.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferError {
+    pub kind: Error,
+    /// The number of frames exchanged before the error occurred.
+    pub transferred: usize,
+}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Self {
+        e.kind
+    }
+}
+
+impl From<Error> for TransferError {
+    fn from(kind: Error) -> Self {
+        Self {
+            kind,
+            transferred: 0,
+        }
+    }
+}
+
+/// Asynchronous delays, used to perform `Operation::DelayNs` and for timeouts.
+#[allow(async_fn_in_trait)]
+pub trait Delay {
+    async fn delay_ns(&mut self, ns: u32);
+
+    /// Wait for the given number of microseconds, which allows longer delays than `delay_ns`.
+    async fn delay_us(&mut self, us: u32) {
+        // Whole seconds are waited for one at a time, so that the nanoseconds fit in a u32.
+        for _ in 0..us / 1_000_000 {
+            self.delay_ns(1_000_000_000).await;
+        }
+        self.delay_ns(us % 1_000_000 * 1_000).await;
+    }
+}
+
+/// Run a transfer, but abort it and return `Error::Timeout` if it does not complete within `us`
+/// microseconds.
+pub async fn timeout<D: Delay, T, E: From<Error>>(
+    delay: &mut D,
+    us: u32,
+    transfer: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let mut transfer = pin!(transfer);
+    let mut expired = pin!(delay.delay_us(us));
+    // Dropping the transfer when the delay has expired aborts it.
+    poll_fn(|cx| {
+        if let Poll::Ready(result) = transfer.as_mut().poll(cx) {
+            Poll::Ready(result)
+        } else if expired.as_mut().poll(cx).is_ready() {
+            Poll::Ready(Err(Error::Timeout.into()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// What the interrupt handler should do for a single operation.
+enum Step {
+    Transfer {
+        tx: *const u8,
+        tx_len: usize,
+        rx: *mut u8,
+        rx_len: usize,
+    },
+    Delay(u32),
+    /// An operation with a checksum, which is performed by the task as transfers of its own.
+    Checksum,
 }
 
+/// Describe the operation at `op`, which must point to an `Operation<W>`. A pointer to this
+/// function is stored in the `Buffer`, so the interrupt handler does not need to know `W`.
+unsafe fn describe<W: Word>(op: *mut u8) -> Step {
+    let (tx, tx_len, rx, rx_len): (*const W, _, *mut W, _) =
+        match &mut *op.cast::<Operation<'static, W>>() {
+            Operation::Read(rx) => (null(), 0, rx.as_mut_ptr(), rx.len()),
+            Operation::Write(tx) => (tx.as_ptr(), tx.len(), null_mut(), 0),
+            Operation::Transfer(rx, tx) => (tx.as_ptr(), tx.len(), rx.as_mut_ptr(), rx.len()),
+            Operation::TransferInPlace(xs) => {
+                let start = xs.as_mut_ptr();
+                (start as *const W, xs.len(), start, xs.len())
+            }
+            Operation::DelayNs(ns) => return Step::Delay(*ns),
+            Operation::WriteWithCrc(..) | Operation::ReadWithCrc(..) => return Step::Checksum,
+        };
+    Step::Transfer {
+        tx: tx.cast(),
+        tx_len,
+        rx: rx.cast(),
+        rx_len,
+    }
+}
+
+/// Describe the `embedded_hal` operation at `op`, which must point to an
+/// `embedded_hal::spi::Operation<u8>`, like `describe`.
+#[cfg(feature = "async-hal")]
+unsafe fn describe_hal(op: *mut u8) -> Step {
+    use embedded_hal::spi::Operation;
+    let (tx, tx_len, rx, rx_len) = match &mut *op.cast::<Operation<'static, u8>>() {
+        Operation::Read(rx) => (null(), 0, rx.as_mut_ptr(), rx.len()),
+        Operation::Write(tx) => (tx.as_ptr(), tx.len(), null_mut(), 0),
+        Operation::Transfer(rx, tx) => (tx.as_ptr(), tx.len(), rx.as_mut_ptr(), rx.len()),
+        Operation::TransferInPlace(xs) => {
+            let start = xs.as_mut_ptr();
+            (start as *const u8, xs.len(), start, xs.len())
+        }
+        Operation::DelayNs(ns) => return Step::Delay(*ns),
+    };
+    Step::Transfer {
+        tx,
+        tx_len,
+        rx,
+        rx_len,
+    }
+}
+
+/// An operation performed by the task rather than the interrupt handler.
+enum Task {
+    Delay(u32),
+    /// A pointer to the `Operation<W>`.
+    Checksum(*mut u8),
+}
+
+#[derive(Clone, Copy)]
 struct Buffer {
-    start: *mut u8,
-    end: *mut u8,
-    /// Whether the SPI will read bytes into the buffer after writing.
-    read: bool,
+    /// The words to write. When these run out, `fill` is written instead.
+    tx_start: *const u8,
+    tx_end: *const u8,
+    /// Where to store the words read. When this runs out, further words are discarded.
+    rx_start: *mut u8,
+    rx_end: *mut u8,
+    fill: u16,
+    /// Where the DMA stores words that are read but discarded.
+    sink: u16,
+    /// Whether the words are `u16` rather than `u8`.
+    wide: bool,
+    /// The block currently being moved by DMA.
+    block: Option<DmaBlock>,
+    /// The operations that have not been started yet.
+    ops_start: *mut u8,
+    ops_end: *mut u8,
+    op_size: usize,
+    describe: unsafe fn(*mut u8) -> Step,
+    /// The number of operations that have been started.
+    started: usize,
+    /// The number of frames exchanged.
+    count: usize,
+    /// The number of frames written but not yet read.
+    in_flight: usize,
+    /// The number of checksum frames to read after the frames in flight.
+    crc: usize,
+    /// The number of frames to discard after turning the data line around to read.
+    turnaround: usize,
+    /// The number of turnaround frames left to read before the frames of the operation.
+    skip: usize,
+    /// The operation for which the direction of the data line was set.
+    turned: usize,
+    /// Whether the frames are clocked by another master, in which case the transfer lasts until
+    /// the master deselects us.
+    slave: bool,
 }
 
 impl Buffer {
     const fn empty() -> Self {
         Self {
-            start: null_mut(),
-            end: null_mut(),
-            read: false,
+            tx_start: null(),
+            tx_end: null(),
+            rx_start: null_mut(),
+            rx_end: null_mut(),
+            fill: 0,
+            sink: 0,
+            wide: false,
+            block: None,
+            ops_start: null_mut(),
+            ops_end: null_mut(),
+            op_size: 0,
+            describe: describe::<u8>,
+            started: 0,
+            count: 0,
+            in_flight: 0,
+            crc: 0,
+            turnaround: 0,
+            skip: 0,
+            turned: 0,
+            slave: false,
+        }
+    }
+
+    fn new<W: Word>(ops: &mut [Operation<W>]) -> Self {
+        let ops_start: *mut u8 = ops.as_mut_ptr().cast();
+        Self {
+            wide: size_of::<W>() == 2,
+            ops_start,
+            ops_end: ops_start.wrapping_add(size_of_val(ops)),
+            op_size: size_of::<Operation<W>>(),
+            describe: describe::<W>,
+            ..Self::empty()
+        }
+    }
+
+    #[cfg(feature = "async-hal")]
+    fn new_hal(ops: &mut [embedded_hal::spi::Operation<u8>]) -> Self {
+        let ops_start: *mut u8 = ops.as_mut_ptr().cast();
+        Self {
+            ops_start,
+            ops_end: ops_start.wrapping_add(size_of_val(ops)),
+            op_size: size_of::<embedded_hal::spi::Operation<u8>>(),
+            describe: describe_hal,
+            ..Self::empty()
+        }
+    }
+
+    fn word_size(&self) -> usize {
+        if self.wide {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Move on to the next operations until there are words left to move. Returns `false` if the
+    /// transfer is complete or the next operation is a delay. Words are moved as long as there are
+    /// words left to write or words left to read.
+    unsafe fn prepare(&mut self) -> bool {
+        while self.tx_start == self.tx_end && self.rx_start == self.rx_end {
+            if !self.start_operation() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Take the next word to write, or `None` if there are no words left to move.
+    unsafe fn next(&mut self) -> Option<u16> {
+        if !self.prepare() {
+            None
+        } else if self.tx_start != self.tx_end {
+            let x = if self.wide {
+                self.tx_start.cast::<u16>().read_unaligned()
+            } else {
+                u16::from(*self.tx_start)
+            };
+            self.tx_start = self.tx_start.add(self.word_size());
+            Some(x)
+        } else {
+            Some(self.fill)
+        }
+    }
+
+    /// Whether the current operation has words left to write, counting the words in flight as
+    /// written. The words of the next operation are only written once no words are in flight
+    /// anymore, because the words read until then belong to the current operation.
+    fn words_left(&self) -> bool {
+        let rx_len = (self.rx_end as usize - self.rx_start as usize) / self.word_size();
+        self.tx_start != self.tx_end || rx_len + self.skip > self.in_flight
+    }
+
+    /// Whether the word written last is the last word of the transfer, which ends at the end of
+    /// the operations or at the next delay.
+    unsafe fn last_word(&self) -> bool {
+        if self.words_left() {
+            return false;
+        }
+        let mut op = self.ops_start;
+        while op != self.ops_end {
+            match (self.describe)(op) {
+                Step::Transfer { tx_len, rx_len, .. } if tx_len > 0 || rx_len > 0 => return false,
+                Step::Transfer { .. } => op = op.add(self.op_size),
+                Step::Delay(_) | Step::Checksum => break,
+            }
+        }
+        true
+    }
+
+    /// Describe the longest block of words that can be moved by DMA, or `None` if there are no
+    /// words left to move. The fill word and sink are used when there is nothing to write or
+    /// nothing to read, which is why the buffer must not move while the block is in progress.
+    unsafe fn next_block(&mut self) -> Option<DmaBlock> {
+        if !self.prepare() {
+            return None;
+        }
+        let tx_len = (self.tx_end as usize - self.tx_start as usize) / self.word_size();
+        let rx_len = (self.rx_end as usize - self.rx_start as usize) / self.word_size();
+        let (tx, tx_increment) = if tx_len > 0 {
+            (self.tx_start, true)
+        } else {
+            (&self.fill as *const u16 as *const u8, false)
+        };
+        let (rx, rx_increment) = if rx_len > 0 {
+            (self.rx_start, true)
+        } else {
+            (&mut self.sink as *mut u16 as *mut u8, false)
+        };
+        let len = match (tx_len, rx_len) {
+            (0, len) | (len, 0) => len,
+            (tx_len, rx_len) => tx_len.min(rx_len),
+        };
+        Some(DmaBlock {
+            tx,
+            tx_increment,
+            rx,
+            rx_increment,
+            len,
+            wide: self.wide,
+        })
+    }
+
+    fn error(&self, kind: Error) -> TransferError {
+        TransferError {
+            kind,
+            transferred: self.count,
+        }
+    }
+
+    /// Advance the cursors past a block that has been moved by DMA.
+    unsafe fn complete(&mut self, block: &DmaBlock) {
+        self.count += block.len;
+        if block.tx_increment {
+            self.tx_start = self.tx_start.add(block.len * self.word_size());
+        }
+        if block.rx_increment {
+            self.rx_start = self.rx_start.add(block.len * self.word_size());
+        }
+    }
+
+    /// Forget the operations and their buffers, keeping only the number of frames exchanged.
+    fn release(&mut self) {
+        *self = Self {
+            count: self.count,
+            ..Self::empty()
+        };
+    }
+
+    /// Store a word that was read, or discard it if the read buffer is full.
+    unsafe fn store(&mut self, x: u16) {
+        self.count += 1;
+        if self.rx_start != self.rx_end {
+            if self.wide {
+                self.rx_start.cast::<u16>().write_unaligned(x);
+            } else {
+                *self.rx_start = x as u8;
+            }
+            self.rx_start = self.rx_start.add(self.word_size());
+        }
+    }
+
+    /// Point the cursors at the buffers of the next operation. Returns `false` if there are no
+    /// operations left or the next operation is a delay, which the interrupt handler can't
+    /// perform.
+    unsafe fn start_operation(&mut self) -> bool {
+        if self.ops_start == self.ops_end {
+            return false;
+        }
+        match (self.describe)(self.ops_start) {
+            Step::Transfer {
+                tx,
+                tx_len,
+                rx,
+                rx_len,
+            } => {
+                self.tx_start = tx;
+                self.tx_end = tx.wrapping_add(tx_len * self.word_size());
+                self.rx_start = rx;
+                self.rx_end = rx.wrapping_add(rx_len * self.word_size());
+            }
+            Step::Delay(_) | Step::Checksum => return false,
+        }
+        self.ops_start = self.ops_start.add(self.op_size);
+        self.started += 1;
+        true
+    }
+
+    /// Skip over the operation the interrupt handler stopped at, returning what the task should
+    /// do for it. Returns `None` if all operations are complete.
+    unsafe fn start_task(&mut self) -> Option<Task> {
+        if self.ops_start == self.ops_end {
+            return None;
+        }
+        let task = match (self.describe)(self.ops_start) {
+            Step::Delay(ns) => Task::Delay(ns),
+            Step::Checksum => Task::Checksum(self.ops_start),
+            Step::Transfer { .. } => return None,
+        };
+        self.ops_start = self.ops_start.add(self.op_size);
+        self.started += 1;
+        Some(task)
+    }
+}
+
+/// The number of words a buffer may take up to be kept by the handler.
+const KEPT_WORDS: usize = 4;
+
+/// A buffer of any type that fits in `KEPT_WORDS` words, which the handler keeps on behalf of the
+/// dropped future of an owned transfer.
+struct Kept {
+    words: MaybeUninit<[usize; KEPT_WORDS]>,
+    /// Drops the buffer stored in `words`, or `None` if no buffer is stored.
+    drop: Option<unsafe fn(*mut u8)>,
+}
+
+impl Kept {
+    const fn empty() -> Self {
+        Self {
+            words: MaybeUninit::uninit(),
+            drop: None,
+        }
+    }
+
+    /// Store `buf`, or give it back if it does not fit.
+    fn new<B>(buf: B) -> Result<Self, B> {
+        unsafe fn drop_buffer<B>(buf: *mut u8) {
+            buf.cast::<B>().drop_in_place()
+        }
+        if size_of::<B>() > size_of::<[usize; KEPT_WORDS]>()
+            || align_of::<B>() > align_of::<usize>()
+        {
+            return Err(buf);
+        }
+        let mut kept = Self {
+            words: MaybeUninit::uninit(),
+            drop: Some(drop_buffer::<B>),
+        };
+        // NOTE(unsafe): The size and alignment of `B` were checked to fit.
+        unsafe { kept.words.as_mut_ptr().cast::<B>().write(buf) };
+        Ok(kept)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.drop.is_none()
+    }
+
+    /// Take the buffer back, which must be a `B`.
+    unsafe fn take<B>(&mut self) -> B {
+        self.drop = None;
+        self.words.as_ptr().cast::<B>().read()
+    }
+}
+
+impl Drop for Kept {
+    fn drop(&mut self) {
+        if let Some(drop) = self.drop {
+            // NOTE(unsafe): `drop` belongs to the type of the buffer that is stored.
+            unsafe { drop(self.words.as_mut_ptr().cast()) }
         }
     }
 }
@@ -52,7 +758,14 @@ pub struct SPIHandler<H> {
     // When the oneshot is empty, the hardware and buf are owned by the interrupt handler,
     // otherwise they are owned by the SPI struct. The interrupt handler controls the sending end
     // of the Oneshot while the SPI struct controls the receiving end.
-    result: Oneshot<Result<(), Error>>,
+    result: Oneshot<Result<(), TransferError>>,
+    /// Whether a `SPI` owns the hardware, between `init` and `SPI::release`.
+    taken: AtomicBool,
+    /// Whether a transfer was started whose future has neither completed nor been dropped. This
+    /// stays set when the future is leaked, so that the transfer can be aborted before the next.
+    running: AtomicBool,
+    /// The buffer of an owned transfer whose future was dropped while the transfer carries on.
+    kept: UnsafeCell<Kept>,
 }
 
 unsafe impl<H> Sync for SPIHandler<H> {}
@@ -66,81 +779,689 @@ impl<H> SPIHandler<H> {
             hardware: UnsafeCell::new(MaybeUninit::uninit()),
             buf: UnsafeCell::new(Buffer::empty()),
             result: Oneshot::new(),
+            taken: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            kept: UnsafeCell::new(Kept::empty()),
         }
     }
 }
 
 impl<H: SPIHardware> SPIHandler<H> {
+    /// Take ownership of the hardware. Panics if a `SPI` of this handler still exists; it must be
+    /// released first.
     pub fn init(&'static self, hardware: H) -> SPI<H> {
-        // Can only be run once because only one instance of H can be safely obtained from the HAL.
-        unsafe { *self.hardware.get() = MaybeUninit::new(hardware) };
-        SPI { handler: self }
+        match self.try_init(hardware) {
+            Ok(spi) => spi,
+            Err(_) => panic!("SPIHandler::init called while a SPI of the handler still exists."),
+        }
+    }
+
+    /// Like `init`, but gives the hardware back if a `SPI` of this handler still exists.
+    pub fn try_init(&'static self, hardware: H) -> Result<SPI<H>, H> {
+        if self.taken.swap(true, Ordering::Acquire) {
+            return Err(hardware);
+        }
+        // NOTE(unsafe): No SPI exists, so no transfer is in progress and the interrupt handler
+        // does not use the hardware.
+        unsafe {
+            *self.hardware.get() = MaybeUninit::new(hardware);
+            (*(&mut *self.hardware.get()).as_mut_ptr()).enable();
+        }
+        Ok(SPI {
+            handler: self,
+            fill: 0,
+            turnaround: 0,
+        })
     }
 
-    /// NOTE(unsafe): Must only be called in the corresponding interrupt handler.
+    /// Move the next frames of the transfer in progress.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called in the corresponding interrupt handler. When it is called from several
+    /// interrupt handlers, such as those of the SPI and a DMA channel, they must have the same
+    /// priority so that one does not preempt the other.
     pub unsafe fn handle_interrupt(&self) {
         // This interrupt handler should only be triggered by operations started by itself or the
         // SPI::transmit method. In either case, the result should be empty which indicates this
         // interrupt handler has ownership over the hardware and buf fields. The interupt handler
         // always has control over the result.put method. The SPI::transmit method must also
-        // guarantee that the buffer is nonempty.
-        debug_assert!(self.result.is_empty());
+        // guarantee that the buffer is nonempty. As slave, the master may clock frames when no
+        // SPI::respond is in progress, which must not be stored in the buffers of the last one.
+        if !self.result.is_empty() {
+            return;
+        }
         let hardware = &mut *(&mut *self.hardware.get()).as_mut_ptr();
         let buf = &mut *self.buf.get();
-        match hardware.read() {
+        if let Some(block) = buf.block {
+            match hardware.poll_dma() {
+                Err(e) => {
+                    buf.block = None;
+                    hardware.recover();
+                    self.result.put(Err(buf.error(e)));
+                }
+                Ok(false) => {}
+                Ok(true) => {
+                    buf.block = None;
+                    buf.complete(&block);
+                    self.proceed(hardware, buf);
+                }
+            }
+            return;
+        }
+
+        let mut received = false;
+        loop {
+            match read(hardware, buf.wide) {
+                Err(e) => {
+                    buf.in_flight = 0;
+                    buf.crc = 0;
+                    buf.skip = 0;
+                    hardware.recover();
+                    // The buffers of a SPI::respond are released once it fails, while the master
+                    // may go on clocking frames.
+                    if buf.slave {
+                        buf.release();
+                    }
+                    self.result.put(Err(buf.error(e)));
+                    return;
+                }
+                Ok(None) => break,
+                Ok(Some(_)) if buf.in_flight == 0 && buf.crc > 0 => {
+                    buf.crc -= 1;
+                    received = true;
+                }
+                Ok(Some(_)) if buf.skip > 0 => {
+                    buf.skip -= 1;
+                    buf.in_flight = buf.in_flight.saturating_sub(1);
+                    received = true;
+                }
+                Ok(Some(x)) => {
+                    // Words are written before they are read, so in-place transfers never
+                    // overwrite a word that still has to be written.
+                    buf.store(x);
+                    // As slave, the master may clock out more frames than were written.
+                    buf.in_flight = buf.in_flight.saturating_sub(1);
+                    received = true;
+                }
+            }
+        }
+        // Frames that arrived while the handler was running were read already, but may have
+        // triggered the interrupt again.
+        if !received {
+            return;
+        }
+        self.proceed(hardware, buf);
+    }
+
+    /// Continue the transfer, or complete it once all words have been moved.
+    unsafe fn proceed(&self, hardware: &mut H, buf: &mut Buffer) {
+        match resume(hardware, buf) {
+            Ok(true) => {}
+            Ok(false) => self.finish(hardware, buf),
+            Err(e) => self.result.put(Err(buf.error(e))),
+        }
+    }
+
+    /// Complete the transfer once all words have been moved, after checking the checksum.
+    unsafe fn finish(&self, hardware: &mut H, buf: &mut Buffer) {
+        hardware.flush();
+        match hardware.check_crc() {
+            Ok(()) => self.result.put(Ok(())),
             Err(e) => {
-                self.result.put(Err(e));
+                hardware.recover();
+                self.result.put(Err(buf.error(e)));
             }
+        }
+    }
 
-            Ok(None) => panic!("SPIHandler::handle_interrupt triggered without new data or error."),
-            Ok(Some(b)) => {
-                debug_assert!(buf.start != buf.end);
-                if buf.read {
-                    *buf.start = b;
+    /// Complete a `SPI::respond` because the master has deselected us.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called in the interrupt handler for the rising edge of NSS, which must have the
+    /// same priority as the interrupt handler calling `handle_interrupt`.
+    pub unsafe fn handle_deselect(&self) {
+        // If the result is not empty, no SPI::respond is in progress. Otherwise this interrupt
+        // handler has ownership over the hardware and buf fields, like handle_interrupt.
+        if !self.result.is_empty() {
+            return;
+        }
+        let hardware = &mut *(&mut *self.hardware.get()).as_mut_ptr();
+        let buf = &mut *self.buf.get();
+        if !buf.slave {
+            return;
+        }
+        // The last frames may have been received just before NSS rose, without the interrupt
+        // handler having run yet.
+        let result = loop {
+            match read(hardware, buf.wide) {
+                Ok(Some(x)) => buf.store(x),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        if result.is_err() {
+            hardware.recover();
+        }
+        // Discard the words that were written in advance but never sent, and stop pointing to the
+        // buffers, which are released once SPI::respond returns.
+        hardware.abort();
+        buf.release();
+        self.result.put(result.map_err(|e| buf.error(e)));
+    }
+}
+
+fn read<H: SPIHardware>(hardware: &H, wide: bool) -> Result<Option<u16>, Error> {
+    if wide {
+        hardware.read_u16()
+    } else {
+        hardware.read().map(|x| x.map(u16::from))
+    }
+}
+
+/// Start moving the next words, using DMA if the hardware supports it, or else writing words until
+/// the fifo is full. Returns `false` if there are no words left to move. As slave, the fill word is
+/// written until the master deselects us.
+unsafe fn resume<H: SPIHardware>(hardware: &mut H, buf: &mut Buffer) -> Result<bool, Error> {
+    let depth = hardware.fifo_depth(buf.wide).max(1);
+    if buf.slave {
+        while buf.in_flight < depth {
+            let x = buf.next().unwrap_or(buf.fill);
+            write(hardware, buf.wide, x);
+            buf.in_flight += 1;
+        }
+        return Ok(true);
+    }
+    if buf.in_flight == 0 {
+        if buf.prepare() && buf.turned != buf.started {
+            buf.turned = buf.started;
+            turn(hardware, buf)?;
+        }
+        if buf.skip == 0 {
+            if let Some(mut block) = buf.next_block() {
+                block.len = hardware.start_dma(&block);
+                if block.len > 0 {
+                    buf.block = Some(block);
+                    return Ok(true);
                 }
-                buf.start = buf.start.wrapping_add(1);
-                if buf.start != buf.end {
-                    hardware.write(*buf.start);
-                } else {
-                    self.result.put(Ok(()));
+            }
+        }
+    }
+    while buf.in_flight < depth && (buf.in_flight == 0 || buf.words_left()) {
+        match buf.next() {
+            Some(x) => {
+                write(hardware, buf.wide, x);
+                buf.in_flight += 1;
+                if buf.last_word() {
+                    buf.crc = hardware.crc_next();
                 }
             }
+            None => break,
+        }
+    }
+    Ok(buf.in_flight > 0 || buf.crc > 0)
+}
+
+/// Turn the data line of half-duplex hardware around for the operation that was just started: to
+/// output if it only writes, to input if it only reads. After turning to input, the turnaround
+/// frames are clocked and discarded first, but not when the line already was an input. Operations
+/// that both write and read fail with `Error::Unsupported` on half-duplex hardware.
+fn turn<H: SPIHardware>(hardware: &mut H, buf: &mut Buffer) -> Result<(), Error> {
+    let tx_len = buf.tx_end as usize - buf.tx_start as usize;
+    let rx_len = buf.rx_end as usize - buf.rx_start as usize;
+    if !hardware.half_duplex() {
+        return Ok(());
+    }
+    let output = match (tx_len, rx_len) {
+        (_, 0) => true,
+        (0, _) => false,
+        _ => return Err(Error::Unsupported),
+    };
+    if hardware.set_direction(output) && !output {
+        buf.skip = buf.turnaround;
+    }
+    Ok(())
+}
+
+fn write<H: SPIHardware>(hardware: &H, wide: bool, x: u16) {
+    if wide {
+        hardware.write_u16(x);
+    } else {
+        hardware.write(x as u8);
+    }
+}
+
+/// Aborts the transfer in progress when dropped. If the future of a transfer is dropped before it
+/// completes, the buffers it borrows are released, so the interrupt handler must stop using them.
+/// The buffer of an owned transfer is handed to the handler instead, and the transfer carries on.
+struct Abort<'a, H: SPIHardware + 'static> {
+    handler: &'static SPIHandler<H>,
+    kept: &'a mut Kept,
+}
+
+impl<H: SPIHardware> Drop for Abort<'_, H> {
+    fn drop(&mut self) {
+        // If the result is not empty, the interrupt handler has already finished the transfer.
+        if !self.handler.result.is_empty() {
+            self.handler.running.store(false, Ordering::Relaxed);
+        } else if !self.kept.is_empty() {
+            // NOTE(unsafe): No buffer is kept by the handler while a transfer is started, so none
+            // is dropped here. The handler is not used concurrently by a task.
+            unsafe { *self.handler.kept.get() = replace(self.kept, Kept::empty()) };
+        } else {
+            self.handler.running.store(false, Ordering::Relaxed);
+            // NOTE(unsafe): The hardware is not used by the interrupt handler anymore once it has
+            // been aborted. The buffer is only used by the interrupt handler while it is called.
+            unsafe {
+                (*(&mut *self.handler.hardware.get()).as_mut_ptr()).abort();
+                (*self.handler.buf.get()).release();
+            }
         }
     }
 }
 
 /// A `SPI` can be obtained by calling `init` on a static `SPIHandler`.
-impl<H: SPIHardware> SPI<H> {
-    async fn begin(&mut self, buf: Buffer) -> Result<(), Error> {
-        if buf.start == buf.end {
-            return Ok(());
+impl<H: SPIHardware, W: Word> SPI<H, W> {
+    /// Set the word that is written during reads, usually all zeros or all ones depending on the
+    /// device. Defaults to zero.
+    pub fn set_fill(&mut self, fill: W) {
+        self.fill = fill;
+    }
+
+    /// On half-duplex hardware, clock and discard `frames` frames after turning the data line
+    /// around to read, for devices that need time to start driving it. Defaults to 0.
+    pub fn set_turnaround(&mut self, frames: usize) {
+        self.turnaround = frames;
+    }
+
+    /// Switch to frames of `bits` bits, which must fit in `V`. Frames are 8 bits after `init`.
+    pub fn with_frame_size<V: Word>(self, bits: u8) -> SPI<H, V> {
+        assert!(
+            (V::MIN_BITS..=V::MAX_BITS).contains(&bits),
+            "SPI::with_frame_size called with a frame size that does not fit the word type."
+        );
+        self.abort_leaked();
+        // NOTE(unsafe): No transfer is in progress because we own the SPI and a transfer of a
+        // leaked future has just been aborted.
+        unsafe { (*(&mut *self.handler.hardware.get()).as_mut_ptr()).set_frame_size(bits) };
+        SPI {
+            handler: self.handler,
+            fill: V::ZERO,
+            turnaround: self.turnaround,
         }
+    }
+
+    /// The number of frames exchanged by the last transfer, including when it failed. When moving
+    /// words with DMA, only the blocks that were completed are counted.
+    pub fn transferred(&self) -> usize {
+        self.abort_leaked();
+        // NOTE(unsafe): No transfer is in progress because the futures of transfers borrow the SPI
+        // mutably and a transfer of a leaked future has just been aborted.
+        unsafe { (*self.handler.buf.get()).count }
+    }
+
+    /// Disable the peripheral and give back the hardware, after which `init` can be called again.
+    pub fn release(self) -> H {
+        self.abort_leaked();
+        // NOTE(unsafe): No transfer is in progress because we own the SPI and a transfer of a
+        // leaked future has just been aborted. The hardware is not used anymore until the next
+        // `init`.
+        let mut hardware = unsafe { (*self.handler.hardware.get()).as_ptr().read() };
+        // The next `init` returns a SPI of `u8` words.
+        hardware.set_frame_size(8);
+        hardware.disable();
+        self.handler.taken.store(false, Ordering::Release);
+        hardware
+    }
+
+    /// Change the mode, bit order and clock frequency. Returns the clock frequency achieved, or
+    /// `Error::Unsupported` if the hardware can not apply the configuration, which then leaves
+    /// the previous one in place.
+    pub fn configure(&mut self, config: &Config) -> Result<u32, Error> {
+        self.abort_leaked();
+        // NOTE(unsafe): No transfer is in progress because we have a mutable reference and a
+        // transfer of a leaked future has just been aborted.
+        unsafe { (*(&mut *self.handler.hardware.get()).as_mut_ptr()).configure(config) }
+    }
+
+    /// Abort the transfer of a future that was leaked instead of dropped, or of an owned transfer
+    /// whose future was dropped, which the interrupt handler may still be performing. The buffer
+    /// kept for the latter is dropped after that.
+    fn abort_leaked(&self) {
+        if self.handler.running.load(Ordering::Relaxed) {
+            drop(Abort {
+                handler: self.handler,
+                kept: &mut Kept::empty(),
+            });
+        }
+        // NOTE(unsafe): The hardware does not access the kept buffer once the transfer is over.
+        unsafe { *self.handler.kept.get() = Kept::empty() };
+    }
+
+    async fn begin(&mut self, new_buf: Buffer) -> Result<(), TransferError> {
+        self.begin_keeping(new_buf, &mut Kept::empty()).await
+    }
+
+    /// Like `begin`, but if the future is dropped before the transfer completes, the buffer in
+    /// `kept` is handed to the handler and the transfer carries on.
+    async fn begin_keeping(
+        &mut self,
+        mut new_buf: Buffer,
+        kept: &mut Kept,
+    ) -> Result<(), TransferError> {
+        // Let the transfer of a buffer kept by the handler complete before it is dropped.
+        // NOTE(unsafe): The buffer is only handed to the handler while the transfer is in progress,
+        // so the result will be sent.
+        if unsafe { !(*self.handler.kept.get()).is_empty() } {
+            let _ = unsafe { self.handler.result.recv() }.await;
+            self.handler.running.store(false, Ordering::Relaxed);
+        }
+        self.abort_leaked();
+        new_buf.fill = self.fill.into_u16();
+        new_buf.turnaround = self.turnaround;
+        // The buffer is moved into the handler first, because a DMA block may point into it.
+        let buf = unsafe {
+            let buf = &mut *self.handler.buf.get();
+            *buf = new_buf;
+            if !buf.prepare() && !buf.slave {
+                return Ok(());
+            }
+            buf
+        };
 
         let recv = unsafe {
             self.handler.result.take();
             self.handler.result.recv()
         };
-        unsafe {
+        let started = unsafe {
             let hardware = &mut *(&mut *self.handler.hardware.get()).as_mut_ptr();
-            let start = buf.start;
-            *self.handler.buf.get() = buf;
-            // Transfer control to the interrupt handler by starting the first byte transmission
-            // which will trigger the interrupt when finished. This must be the last operation
-            // before awaiting the reception of the result.
-            hardware.write(*start);
+            // Transfer control to the interrupt handler by starting the first transmission which
+            // will trigger the interrupt when finished. This must be the last operation before
+            // awaiting the reception of the result.
+            resume(hardware, buf)
+        };
+        // Nothing was written if the first operation can not be performed. The result is put back,
+        // because no transfer is in progress.
+        if let Err(e) = started {
+            let e = buf.error(e);
+            self.handler.result.put(Err(e));
+            return Err(e);
+        }
+        self.handler.running.store(true, Ordering::Relaxed);
+        let abort = Abort {
+            handler: self.handler,
+            kept,
+        };
+        let result = recv.await;
+        self.handler.running.store(false, Ordering::Relaxed);
+        core::mem::forget(abort);
+        result
+    }
+
+    /// Perform the operations one after the other. Consecutive transfers are chained by the
+    /// interrupt handler without returning to the task in between; only delays and operations
+    /// with a checksum are performed by the task, the delays using `delay`.
+    pub async fn transaction<D: Delay>(
+        &mut self,
+        ops: &mut [Operation<'_, W>],
+        delay: &mut D,
+    ) -> Result<(), OperationError> {
+        self.chain(Buffer::new(ops), delay).await
+    }
+
+    /// Perform the operations of `buf`, which describes either `Operation<W>` or operations
+    /// without a checksum.
+    async fn chain<D: Delay>(
+        &mut self,
+        mut buf: Buffer,
+        delay: &mut D,
+    ) -> Result<(), OperationError> {
+        loop {
+            let result = self.begin(buf).await;
+            // NOTE(unsafe): The transfer is complete, so the buffer is no longer owned by the
+            // interrupt handler.
+            buf = unsafe { *self.handler.buf.get() };
+            if let Err(e) = result {
+                return Err(OperationError {
+                    index: buf.started - 1,
+                    error: e.kind,
+                });
+            }
+            match unsafe { buf.start_task() } {
+                Some(Task::Delay(ns)) => delay.delay_ns(ns).await,
+                Some(Task::Checksum(op)) => {
+                    // NOTE(unsafe): The operation is not used by the interrupt handler, which
+                    // stopped in front of it. Only `Operation<W>` has a checksum.
+                    let op = unsafe { &mut *op.cast::<Operation<'_, W>>() };
+                    let result = self.checked(op).await;
+                    // Count the words of the operation along with those of the transaction.
+                    unsafe {
+                        let inner = &mut *self.handler.buf.get();
+                        buf.count += inner.count;
+                        inner.count = buf.count;
+                    }
+                    if let Err(e) = result {
+                        return Err(OperationError {
+                            index: buf.started - 1,
+                            error: e.kind,
+                        });
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Perform a `WriteWithCrc` or `ReadWithCrc` operation as a transfer of its own.
+    async fn checked(&mut self, op: &mut Operation<'_, W>) -> Result<(), TransferError> {
+        let mut trailer = [W::ZERO; 2];
+        match op {
+            Operation::WriteWithCrc(xs, checksum) => {
+                checksum.reset();
+                crc::update(*checksum, xs);
+                let len = match crc::trailer(*checksum, &mut trailer) {
+                    Some(len) => len,
+                    None => return Err(self.unsupported()),
+                };
+                let mut ops = [Operation::Write(xs), Operation::Write(&trailer[..len])];
+                self.begin(Buffer::new(&mut ops)).await
+            }
+            Operation::ReadWithCrc(xs, checksum) => {
+                let len = match crc::trailer_len::<W>(*checksum) {
+                    Some(len) => len,
+                    None => return Err(self.unsupported()),
+                };
+                let mut ops = [Operation::Read(xs), Operation::Read(&mut trailer[..len])];
+                self.begin(Buffer::new(&mut ops)).await?;
+                checksum.reset();
+                crc::update(*checksum, xs);
+                if crc::join(&trailer[..len]) != checksum.trailer() {
+                    return Err(TransferError {
+                        kind: Error::BadChecksum,
+                        transferred: self.transferred(),
+                    });
+                }
+                Ok(())
+            }
+            _ => unreachable!(),
         }
-        recv.await
     }
 
-    pub async fn transmit(&mut self, xs: &mut [u8]) -> Result<(), Error> {
-        let start = xs.as_mut_ptr();
-        let end = start.wrapping_add(xs.len());
-        self.begin(Buffer{start, end, read: true}).await
+    /// Fail an operation with a checksum that does not fit the words, before anything is
+    /// transferred.
+    fn unsupported(&mut self) -> TransferError {
+        // NOTE(unsafe): No transfer is in progress. The buffer is replaced so the words transferred
+        // by the operation are counted as none.
+        unsafe { *self.handler.buf.get() = Buffer::empty() };
+        TransferError {
+            kind: Error::Unsupported,
+            transferred: 0,
+        }
+    }
+
+    async fn single(&mut self, mut op: Operation<'_, W>) -> Result<(), TransferError> {
+        self.begin(Buffer::new(core::slice::from_mut(&mut op)))
+            .await
+    }
+
+    /// Write the words in `xs` and replace them with the words read.
+    pub async fn transmit(&mut self, xs: &mut [W]) -> Result<(), TransferError> {
+        self.single(Operation::TransferInPlace(xs)).await
+    }
+
+    /// Write the words in `write` while reading into `read`. The transfer lasts as long as the
+    /// longest of the two: if `write` is shorter it is padded with the fill word, if `read` is
+    /// shorter the remaining words read are discarded.
+    pub async fn transfer(&mut self, read: &mut [W], write: &[W]) -> Result<(), TransferError> {
+        self.single(Operation::Transfer(read, write)).await
+    }
+
+    /// Read into `xs` while writing the fill word.
+    pub async fn read(&mut self, xs: &mut [W]) -> Result<(), TransferError> {
+        self.single(Operation::Read(xs)).await
+    }
+
+    pub async fn write(&mut self, xs: &[W]) -> Result<(), TransferError> {
+        self.single(Operation::Write(xs)).await
+    }
+
+    /// Write the words in `write`, then read into `read`. On half-duplex hardware, the data line
+    /// is turned around in between.
+    pub async fn write_read(&mut self, write: &[W], read: &mut [W]) -> Result<(), TransferError> {
+        let mut ops = [Operation::Write(write), Operation::Read(read)];
+        self.begin(Buffer::new(&mut ops)).await
+    }
+
+    /// Like `transmit`, but takes ownership of the buffer and gives it back along with the result.
+    ///
+    /// If the future is dropped before the transfer completes, the buffer is handed to the handler,
+    /// which keeps it until the hardware is done with it: the next transfer waits for the transfer
+    /// to complete before dropping the buffer, while `release`, `configure`, `with_frame_size`
+    /// and `transferred` abort it first. Buffers that take up more than four words are not handed
+    /// over; their transfer is aborted when the future is dropped, before the buffer is dropped
+    /// along with the future. If the future is leaked, the buffer is never dropped, and the
+    /// transfer is aborted by the next transfer or by `release`, so that it does not run on under
+    /// another. Either way, the hardware never accesses words that have been freed or handed out
+    /// again.
+    pub async fn transfer_owned<B: WriteBuffer<Word = W> + Send>(
+        &mut self,
+        mut buf: B,
+    ) -> (B, Result<(), TransferError>) {
+        // NOTE(unsafe): The words are only accessed through the operation, which lives as long as
+        // the transfer, and are not freed before `buf` is dropped after the transfer.
+        let xs = unsafe {
+            let (start, len) = buf.write_buffer();
+            core::slice::from_raw_parts_mut(start, len)
+        };
+        self.owned(Operation::TransferInPlace(xs), buf).await
+    }
+
+    /// Like `write`, but takes ownership of the buffer like `transfer_owned`.
+    pub async fn write_owned<B: ReadBuffer<Word = W> + Send>(
+        &mut self,
+        buf: B,
+    ) -> (B, Result<(), TransferError>) {
+        // NOTE(unsafe): See `transfer_owned`.
+        let xs = unsafe {
+            let (start, len) = buf.read_buffer();
+            core::slice::from_raw_parts(start, len)
+        };
+        self.owned(Operation::Write(xs), buf).await
     }
 
-    pub async fn write(&mut self, xs: &[u8]) -> Result<(), Error> {
-        let start = xs.as_ptr() as *mut u8;
-        let end = start.wrapping_add(xs.len());
-        self.begin(Buffer{start, end, read: false}).await
+    /// Like `read`, but takes ownership of the buffer like `transfer_owned`.
+    pub async fn read_owned<B: WriteBuffer<Word = W> + Send>(
+        &mut self,
+        mut buf: B,
+    ) -> (B, Result<(), TransferError>) {
+        // NOTE(unsafe): See `transfer_owned`.
+        let xs = unsafe {
+            let (start, len) = buf.write_buffer();
+            core::slice::from_raw_parts_mut(start, len)
+        };
+        self.owned(Operation::Read(xs), buf).await
+    }
+
+    /// Perform `op`, which accesses the words of `buf`, keeping `buf` in the handler if the future
+    /// is dropped before the transfer completes. The operation itself is only used to start the
+    /// transfer, so it may be dropped with the future.
+    async fn owned<B: Send>(
+        &mut self,
+        mut op: Operation<'_, W>,
+        buf: B,
+    ) -> (B, Result<(), TransferError>) {
+        let mut kept = match Kept::new(buf) {
+            Ok(kept) => kept,
+            Err(buf) => {
+                let result = self.single(op).await;
+                return (buf, result);
+            }
+        };
+        let ops = core::slice::from_mut(&mut op);
+        let result = self.begin_keeping(Buffer::new(ops), &mut kept).await;
+        // NOTE(unsafe): The transfer is complete, so the buffer was not handed to the handler.
+        (unsafe { kept.take() }, result)
+    }
+
+    /// Act as slave: write `tx` and read into `rx` while the master clocks the frames, until the
+    /// master deselects us. Returns the number of frames exchanged. If the master clocks more
+    /// frames than `tx` holds, the fill word is written; if it clocks more frames than `rx` can
+    /// hold, the rest is discarded.
+    ///
+    /// The hardware must be set up as slave, and `SPIHandler::handle_deselect` must be called when
+    /// NSS rises.
+    pub async fn respond(&mut self, tx: &[W], rx: &mut [W]) -> Result<usize, TransferError> {
+        let mut op = Operation::Transfer(rx, tx);
+        let mut buf = Buffer::new(core::slice::from_mut(&mut op));
+        buf.slave = true;
+        self.begin(buf).await?;
+        // NOTE(unsafe): The transfer is complete, so the buffer is no longer owned by the
+        // interrupt handler.
+        Ok(unsafe { (*self.handler.buf.get()).count })
+    }
+
+    /// Like `transmit`, but fails with `Error::Timeout` after `us` microseconds.
+    pub async fn transmit_with_timeout<D: Delay>(
+        &mut self,
+        xs: &mut [W],
+        delay: &mut D,
+        us: u32,
+    ) -> Result<(), TransferError> {
+        let result = timeout(delay, us, self.transmit(xs)).await;
+        self.count_timeout(result)
+    }
+
+    /// Like `write`, but fails with `Error::Timeout` after `us` microseconds.
+    pub async fn write_with_timeout<D: Delay>(
+        &mut self,
+        xs: &[W],
+        delay: &mut D,
+        us: u32,
+    ) -> Result<(), TransferError> {
+        let result = timeout(delay, us, self.write(xs)).await;
+        self.count_timeout(result)
+    }
+
+    /// Fill in the number of frames transferred before a timeout aborted the transfer.
+    fn count_timeout(&self, result: Result<(), TransferError>) -> Result<(), TransferError> {
+        result.map_err(|mut e| {
+            if let Error::Timeout = e.kind {
+                e.transferred = self.transferred();
+            }
+            e
+        })
+    }
+}
+
+#[cfg(feature = "async-hal")]
+impl<H: SPIHardware> SPI<H> {
+    /// Perform `embedded_hal` operations like `transaction`.
+    pub(crate) async fn hal_transaction<D: Delay>(
+        &mut self,
+        ops: &mut [embedded_hal::spi::Operation<'_, u8>],
+        delay: &mut D,
+    ) -> Result<(), OperationError> {
+        self.chain(Buffer::new_hal(ops), delay).await
     }
 }
@@ -0,0 +1,261 @@
+//! Checksums calculated in software, for protocols whose checksum the hardware CRC can not
+//! produce. They are used by `Operation::WriteWithCrc` and `Operation::ReadWithCrc`.
+
+use core::mem::size_of;
+
+use crate::Word;
+
+/// A checksum calculated one byte at a time.
+pub trait Checksum {
+    /// Start over, as if no bytes had been added.
+    fn reset(&mut self);
+    /// Add bytes to the checksum.
+    fn update(&mut self, bytes: &[u8]);
+    /// The checksum of the bytes added so far, as it is sent.
+    fn trailer(&self) -> u16;
+    /// The number of bytes taken by the trailer: 1 or 2.
+    fn bytes(&self) -> usize;
+}
+
+/// CRC7 with polynomial x^7 + x^3 + 1 and initial value 0, as used by the commands of SD cards.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc7 {
+    crc: u8,
+}
+
+impl Crc7 {
+    pub const fn new() -> Self {
+        Self { crc: 0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            for i in (0..8).rev() {
+                let bit = (byte >> i) & 1 ^ self.crc >> 6;
+                self.crc = (self.crc << 1) & 0x7f;
+                if bit != 0 {
+                    self.crc ^= 0x09;
+                }
+            }
+        }
+    }
+
+    /// The 7-bit checksum.
+    pub fn value(&self) -> u8 {
+        self.crc
+    }
+}
+
+impl Checksum for Crc7 {
+    fn reset(&mut self) {
+        self.crc = 0;
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        Crc7::update(self, bytes)
+    }
+
+    /// The checksum followed by the end bit, which makes up the last byte of an SD command.
+    fn trailer(&self) -> u16 {
+        u16::from(self.crc) << 1 | 1
+    }
+
+    fn bytes(&self) -> usize {
+        1
+    }
+}
+
+/// CRC16-CCITT with polynomial x^16 + x^12 + x^5 + 1, as used by the data blocks of SD cards with
+/// the initial value 0.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc16 {
+    init: u16,
+    crc: u16,
+}
+
+impl Crc16 {
+    /// Start with the value 0, also known as XMODEM.
+    pub const fn new() -> Self {
+        Self::with_init(0)
+    }
+
+    /// Start with another value, such as 0xffff.
+    pub const fn with_init(init: u16) -> Self {
+        Self { init, crc: init }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.crc ^= u16::from(byte) << 8;
+            for _ in 0..8 {
+                self.crc = if self.crc & 0x8000 != 0 {
+                    self.crc << 1 ^ 0x1021
+                } else {
+                    self.crc << 1
+                };
+            }
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        self.crc
+    }
+}
+
+impl Checksum for Crc16 {
+    fn reset(&mut self) {
+        self.crc = self.init;
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        Crc16::update(self, bytes)
+    }
+
+    fn trailer(&self) -> u16 {
+        self.crc
+    }
+
+    fn bytes(&self) -> usize {
+        2
+    }
+}
+
+/// CRC8 with any polynomial and initial value, such as polynomial 0x31 with initial value 0xff
+/// used by many sensors, or polynomial 0x07 with initial value 0 used by SMBus. The polynomial
+/// leaves out the x^8 term.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc8 {
+    polynomial: u8,
+    init: u8,
+    crc: u8,
+}
+
+impl Crc8 {
+    pub const fn new(polynomial: u8, init: u8) -> Self {
+        Self {
+            polynomial,
+            init,
+            crc: init,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.crc ^= byte;
+            for _ in 0..8 {
+                self.crc = if self.crc & 0x80 != 0 {
+                    self.crc << 1 ^ self.polynomial
+                } else {
+                    self.crc << 1
+                };
+            }
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        self.crc
+    }
+}
+
+impl Checksum for Crc8 {
+    fn reset(&mut self) {
+        self.crc = self.init;
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        Crc8::update(self, bytes)
+    }
+
+    fn trailer(&self) -> u16 {
+        u16::from(self.crc)
+    }
+
+    fn bytes(&self) -> usize {
+        1
+    }
+}
+
+/// Add words to the checksum. Words of 16 bits are added most significant byte first.
+pub(crate) fn update<W: Word>(crc: &mut dyn Checksum, xs: &[W]) {
+    for x in xs {
+        let x = x.into_u16();
+        if size_of::<W>() == 2 {
+            crc.update(&x.to_be_bytes());
+        } else {
+            crc.update(&[x as u8]);
+        }
+    }
+}
+
+/// The number of words taken by the trailer: a single word of 16 bits, or else one word per byte.
+/// Returns `None` for a checksum of one byte with words of 16 bits, which can not be sent on its
+/// own.
+pub(crate) fn trailer_len<W: Word>(crc: &dyn Checksum) -> Option<usize> {
+    match (size_of::<W>(), crc.bytes()) {
+        (2, 2) => Some(1),
+        (2, _) => None,
+        (_, bytes) => Some(bytes),
+    }
+}
+
+/// Split the trailer into words, most significant byte first. Returns the number of words, or
+/// `None` like `trailer_len`.
+pub(crate) fn trailer<W: Word>(crc: &dyn Checksum, words: &mut [W; 2]) -> Option<usize> {
+    let len = trailer_len::<W>(crc)?;
+    let x = crc.trailer();
+    for (i, word) in words[..len].iter_mut().enumerate() {
+        let shift = if len == 2 { 8 * (1 - i) } else { 0 };
+        *word = W::from_u16(x >> shift);
+    }
+    Some(len)
+}
+
+/// Join the words of a trailer received.
+pub(crate) fn join<W: Word>(words: &[W]) -> u16 {
+    let shift = if size_of::<W>() == 2 { 0 } else { 8 };
+    words
+        .iter()
+        .fold(0, |acc, x| acc.wrapping_shl(shift) | x.into_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc7_sd_commands() {
+        let mut crc = Crc7::new();
+        crc.update(&[0x40, 0, 0, 0, 0]);
+        assert_eq!(crc.trailer(), 0x95);
+        crc.reset();
+        crc.update(&[0x48, 0, 0, 1, 0xaa]);
+        assert_eq!(crc.trailer(), 0x87);
+    }
+
+    #[test]
+    fn crc16_xmodem() {
+        let mut crc = Crc16::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.value(), 0x31c3);
+    }
+
+    #[test]
+    fn crc8_sensirion() {
+        let mut crc = Crc8::new(0x31, 0xff);
+        crc.update(&[0xbe, 0xef]);
+        assert_eq!(crc.value(), 0x92);
+    }
+
+    #[test]
+    fn trailer_words() {
+        let mut crc = Crc16::new();
+        crc.update(b"123456789");
+        let mut bytes = [0u8; 2];
+        assert_eq!(trailer(&crc, &mut bytes), Some(2));
+        assert_eq!(bytes, [0x31, 0xc3]);
+        let mut words = [0u16; 2];
+        assert_eq!(trailer(&crc, &mut words), Some(1));
+        assert_eq!(words[0], 0x31c3);
+        assert_eq!(trailer_len::<u16>(&Crc8::new(0x31, 0xff)), None);
+    }
+}
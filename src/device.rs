@@ -0,0 +1,97 @@
+//! A `SPIDevice` combines a `SPI` with a chip select pin, so that a sequence of operations can be
+//! performed on a single device as one transaction.
+use core::fmt::Debug;
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{ErrorKind, ErrorType, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi::SpiDevice;
+
+use crate::{Error, HalDelay, SPIHardware, SPI};
+
+#[derive(Clone, Copy, Debug)]
+pub enum DeviceError<CS> {
+    /// The transfer itself failed.
+    SPI(Error),
+    /// Setting the chip select pin failed.
+    ChipSelect(CS),
+}
+
+impl<CS: Debug> embedded_hal::spi::Error for DeviceError<CS> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DeviceError::SPI(e) => embedded_hal::spi::Error::kind(e),
+            DeviceError::ChipSelect(_) => ErrorKind::ChipSelectFault,
+        }
+    }
+}
+
+/// A device with an active low chip select pin that has exclusive access to a `SPI`. The delay is
+/// used for `Operation::DelayNs`.
+pub struct SPIDevice<H: 'static, CS, D> {
+    spi: SPI<H>,
+    cs: CS,
+    delay: D,
+}
+
+impl<H: SPIHardware, CS: OutputPin, D: DelayNs> SPIDevice<H, CS, D> {
+    /// Create a new device, deasserting the chip select pin.
+    pub fn new(spi: SPI<H>, mut cs: CS, delay: D) -> Result<Self, CS::Error> {
+        cs.set_high()?;
+        Ok(Self { spi, cs, delay })
+    }
+
+    /// Set the byte written during reads, like `SPI::set_fill`.
+    pub fn set_fill(&mut self, fill: u8) {
+        self.spi.set_fill(fill);
+    }
+
+    pub fn release(self) -> (SPI<H>, CS, D) {
+        (self.spi, self.cs, self.delay)
+    }
+}
+
+/// Deasserts the chip select when the future of a transaction is dropped, so that it is not left
+/// asserted while other devices on the bus are selected.
+struct Deselect<'a, CS: OutputPin>(&'a mut CS);
+
+impl<CS: OutputPin> Drop for Deselect<'_, CS> {
+    fn drop(&mut self) {
+        let _ = self.0.set_high();
+    }
+}
+
+/// Perform the operations while the chip select is asserted, chaining consecutive transfers in the
+/// interrupt handler like `SPI::transaction`.
+pub(crate) async fn transaction<H: SPIHardware, CS: OutputPin, D: DelayNs>(
+    spi: &mut SPI<H>,
+    cs: &mut CS,
+    delay: &mut D,
+    operations: &mut [Operation<'_, u8>],
+) -> Result<(), DeviceError<CS::Error>> {
+    cs.set_low().map_err(DeviceError::ChipSelect)?;
+    let deselect = Deselect(&mut *cs);
+    let result = spi
+        .hal_transaction(operations, &mut HalDelay(delay))
+        .await
+        .map_err(|e| e.error);
+    core::mem::forget(deselect);
+    // The chip select is deasserted even if an operation failed, so the device can tell the
+    // transaction has ended.
+    let deassert = cs.set_high().map_err(DeviceError::ChipSelect);
+    result.map_err(DeviceError::SPI)?;
+    deassert
+}
+
+impl<H: SPIHardware, CS: OutputPin, D> ErrorType for SPIDevice<H, CS, D> {
+    type Error = DeviceError<CS::Error>;
+}
+
+impl<H: SPIHardware, CS: OutputPin, D: DelayNs> SpiDevice for SPIDevice<H, CS, D> {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        transaction(&mut self.spi, &mut self.cs, &mut self.delay, operations).await
+    }
+}
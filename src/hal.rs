@@ -0,0 +1,62 @@
+//! Implementations of the `embedded-hal-async` traits, so drivers written against those traits can
+//! run on top of a `SPI`.
+use embedded_hal::spi::{ErrorKind, ErrorType};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi::SpiBus;
+
+use crate::{Delay, Error, SPIHardware, Word, SPI};
+
+/// Adapts an `embedded-hal-async` delay to `Delay`, for transactions and timeouts.
+pub struct HalDelay<D>(pub D);
+
+impl<D: DelayNs> Delay for HalDelay<D> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_ns(ns).await
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        self.0.delay_us(us).await
+    }
+}
+
+impl embedded_hal::spi::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::BadFrameFormat => ErrorKind::FrameFormat,
+            Error::Overrun => ErrorKind::Overrun,
+            Error::ModeFault => ErrorKind::ModeFault,
+            Error::BadChecksum
+            | Error::Uninitialized
+            | Error::Dma
+            | Error::Timeout
+            | Error::Unsupported => ErrorKind::Other,
+        }
+    }
+}
+
+impl<H: SPIHardware, W: Word> ErrorType for SPI<H, W> {
+    type Error = Error;
+}
+
+impl<H: SPIHardware, W: Word> SpiBus<W> for SPI<H, W> {
+    async fn read(&mut self, words: &mut [W]) -> Result<(), Error> {
+        SPI::read(self, words).await.map_err(Error::from)
+    }
+
+    async fn write(&mut self, words: &[W]) -> Result<(), Error> {
+        SPI::write(self, words).await.map_err(Error::from)
+    }
+
+    async fn transfer(&mut self, read: &mut [W], write: &[W]) -> Result<(), Error> {
+        SPI::transfer(self, read, write).await.map_err(Error::from)
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [W]) -> Result<(), Error> {
+        self.transmit(words).await.map_err(Error::from)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        // Every transfer has finished by the time its future completes.
+        Ok(())
+    }
+}
@@ -4,5 +4,29 @@
 mod common;
 pub use common::*;
 
+mod crc;
+pub use crc::*;
+
+mod buffer;
+pub use buffer::*;
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
 #[cfg(feature = "stm32l4x6")]
-mod stm32l4x6;
+pub mod stm32l4x6;
+
+#[cfg(feature = "async-hal")]
+mod device;
+#[cfg(feature = "async-hal")]
+pub use device::*;
+
+#[cfg(feature = "async-hal")]
+mod hal;
+#[cfg(feature = "async-hal")]
+pub use hal::*;
+
+#[cfg(feature = "async-hal")]
+mod shared;
+#[cfg(feature = "async-hal")]
+pub use shared::*;
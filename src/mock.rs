@@ -0,0 +1,530 @@
+//! A `MockHardware` for testing drivers on the host. It checks the frames written against a script
+//! of expected frames and answers them with the frames or errors from the script. Delays are
+//! measured on a simulated clock, which only advances when nothing else can make progress.
+extern crate std;
+
+use core::fmt;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::collections::VecDeque;
+use std::string::{String, ToString};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec::Vec;
+
+use crate::{Config, Delay, DmaBlock, Error, FrameFormat, Phase, SPIHandler, SPIHardware};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Expectation {
+    /// Expect `write` to be written, and answer with `read`.
+    Transfer { write: u16, read: u16 },
+    /// Expect `write` to be written, and answer with `error`.
+    Error { write: u16, error: Error },
+    /// Expect `write` to be written, and never finish the frame, like a wedged peripheral or a
+    /// master that stops clocking. The frame and those written after it stay queued for
+    /// transmission until the transfer is aborted or the hardware is recovered.
+    Stall { write: u16 },
+}
+
+impl Expectation {
+    pub fn transfer(write: u16, read: u16) -> Self {
+        Expectation::Transfer { write, read }
+    }
+
+    pub fn error(write: u16, error: Error) -> Self {
+        Expectation::Error { write, error }
+    }
+
+    pub fn stall(write: u16) -> Self {
+        Expectation::Stall { write }
+    }
+}
+
+/// A frame that was written but did not match the script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The index of the frame since the start of the script.
+    pub index: usize,
+    /// The frame the script expected, or `None` if the script had already ended.
+    pub expected: Option<u16>,
+    pub actual: u16,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "frame {}: expected {:#06x}, written {:#06x}",
+                self.index, expected, self.actual
+            ),
+            None => write!(
+                f,
+                "frame {}: expected nothing, written {:#06x}",
+                self.index, self.actual
+            ),
+        }
+    }
+}
+
+struct State {
+    script: VecDeque<Expectation>,
+    /// The number of frames written.
+    index: usize,
+    /// The answers to the frames written but not yet read, in order, or the outcome of the block
+    /// moved by DMA. A frame or block that never finishes is `None`, and holds up the frames
+    /// written after it.
+    pending: VecDeque<Option<Result<u16, Error>>>,
+    /// The frames queued for transmission that are not clocked, from the stalled frame on.
+    unclocked: VecDeque<u16>,
+    /// The number of frames the receive fifo holds.
+    depth: usize,
+    /// The number of frames of the checksum sent after the last word of every transfer.
+    crc: usize,
+    /// The number of frames written when a checksum was sent.
+    checksums: Vec<usize>,
+    /// Whether the checksum received by the next transfer does not match.
+    bad_crc: bool,
+    /// Whether words are moved in blocks by DMA.
+    dma: bool,
+    /// The number of blocks moved by DMA.
+    blocks: usize,
+    mismatches: Vec<Mismatch>,
+    frame_size: u8,
+    config: Option<Config>,
+    configurations: usize,
+    aborts: usize,
+    recoveries: usize,
+    /// Whether the data is moved over a single, bidirectional line.
+    half_duplex: bool,
+    /// Whether the half-duplex data line is an output.
+    output: bool,
+    /// The simulated time in nanoseconds.
+    now: u64,
+    /// The deadlines of the delays in progress.
+    deadlines: Vec<u64>,
+}
+
+/// Hardware that follows a script instead of talking to a peripheral. Clones share the same
+/// state, so one clone can be given to `SPIHandler::init` while another is used to check the
+/// results.
+#[derive(Clone)]
+pub struct MockHardware {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockHardware {
+    pub fn new(script: &[Expectation]) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                script: script.iter().copied().collect(),
+                index: 0,
+                pending: VecDeque::new(),
+                unclocked: VecDeque::new(),
+                depth: 1,
+                crc: 0,
+                checksums: Vec::new(),
+                bad_crc: false,
+                dma: false,
+                blocks: 0,
+                mismatches: Vec::new(),
+                frame_size: 8,
+                config: None,
+                configurations: 0,
+                aborts: 0,
+                recoveries: 0,
+                half_duplex: false,
+                output: true,
+                now: 0,
+                deadlines: Vec::new(),
+            })),
+        }
+    }
+
+    /// Act like hardware with a single, bidirectional data line, which starts out as an output.
+    pub fn with_half_duplex(self) -> Self {
+        self.state().half_duplex = true;
+        self
+    }
+
+    /// Act like hardware with fifos of `depth` frames, so that the interrupt handler keeps that
+    /// many frames in flight.
+    pub fn with_fifo_depth(self, depth: usize) -> Self {
+        self.state().depth = depth;
+        self
+    }
+
+    /// Act like hardware that sends a checksum of `frames` frames after the last word of every
+    /// transfer. The frames are answered with zeros, without being checked against the script.
+    pub fn with_crc(self, frames: usize) -> Self {
+        self.state().crc = frames;
+        self
+    }
+
+    /// Act like hardware that moves blocks of words by DMA. Every word of a block is checked
+    /// against the script, and the block fails with the error of the first word answered with
+    /// one.
+    pub fn with_dma(self) -> Self {
+        self.state().dma = true;
+        self
+    }
+
+    /// Make the checksum received by the next transfer not match, so that it fails with
+    /// `Error::BadChecksum`.
+    pub fn fail_crc(&self) {
+        self.state().bad_crc = true;
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Add expectations to the end of the script.
+    pub fn expect(&self, script: &[Expectation]) {
+        self.state().script.extend(script.iter().copied());
+    }
+
+    /// The frames written so far that did not match the script.
+    pub fn mismatches(&self) -> Vec<Mismatch> {
+        self.state().mismatches.clone()
+    }
+
+    /// The frame size last set through `SPIHardware::set_frame_size`.
+    pub fn frame_size(&self) -> u8 {
+        self.state().frame_size
+    }
+
+    /// The configuration last applied through `SPIHardware::configure`.
+    pub fn config(&self) -> Option<Config> {
+        self.state().config
+    }
+
+    /// The number of times a configuration was applied.
+    pub fn configurations(&self) -> usize {
+        self.state().configurations
+    }
+
+    /// Whether the half-duplex data line is an output.
+    pub fn output(&self) -> bool {
+        self.state().output
+    }
+
+    /// The number of frames that had been written every time a checksum was sent.
+    pub fn checksums(&self) -> Vec<usize> {
+        self.state().checksums.clone()
+    }
+
+    /// The number of blocks moved by DMA.
+    pub fn blocks(&self) -> usize {
+        self.state().blocks
+    }
+
+    /// The number of times a transfer was aborted.
+    pub fn aborts(&self) -> usize {
+        self.state().aborts
+    }
+
+    /// The number of times the hardware was recovered after an error.
+    pub fn recoveries(&self) -> usize {
+        self.state().recoveries
+    }
+
+    /// The simulated time in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.state().now
+    }
+
+    /// A delay measured on the simulated clock.
+    pub fn delay(&self) -> MockDelay {
+        MockDelay {
+            hardware: self.clone(),
+        }
+    }
+
+    /// Panic if a frame did not match the script or the script was not completed.
+    pub fn done(&self) {
+        let state = self.state();
+        let mismatches: Vec<String> = state.mismatches.iter().map(ToString::to_string).collect();
+        assert!(
+            mismatches.is_empty(),
+            "MockHardware: {} frames did not match the script: {}.",
+            mismatches.len(),
+            mismatches.join("; ")
+        );
+        assert!(
+            state.script.is_empty(),
+            "MockHardware: {} expected frames were not written.",
+            state.script.len()
+        );
+    }
+
+    /// Run `future` to completion. Whenever it can't make progress, the interrupt handler is
+    /// called as if the peripheral had finished the frames in flight, until none is left or the
+    /// first one never finishes. If no frame finishes, the simulated clock is advanced to the
+    /// first deadline of a delay.
+    ///
+    /// Panics if the future can't make progress while no frame or delay is in progress.
+    pub fn block_on<F: Future>(&self, handler: &SPIHandler<MockHardware>, future: F) -> F::Output {
+        let mut future = pin!(future);
+        // NOTE(unsafe): The waker does nothing, because the future is polled continuously.
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(x) = future.as_mut().poll(&mut cx) {
+                return x;
+            }
+            if !self.finished() {
+                let mut state = self.state();
+                let deadline = state.deadlines.iter().copied().min().expect(
+                    "MockHardware::block_on: the future is pending but no frame or delay is in \
+                     progress.",
+                );
+                state.now = state.now.max(deadline);
+            }
+            while self.finished() {
+                // NOTE(unsafe): This simulates the interrupt handler, which does not run
+                // concurrently with the future.
+                unsafe { handler.handle_interrupt() };
+            }
+        }
+    }
+
+    /// Whether the first frame in flight has finished, so it can be read.
+    fn finished(&self) -> bool {
+        matches!(self.state().pending.front(), Some(Some(_)))
+    }
+
+    fn take(&self) -> Result<Option<u16>, Error> {
+        if !self.finished() {
+            return Ok(None);
+        }
+        self.state().pending.pop_front().flatten().transpose()
+    }
+
+    fn put(&self, actual: u16) {
+        // Frames are not checked against the script until they are clocked.
+        if !self.state().unclocked.is_empty() {
+            self.state().unclocked.push_back(actual);
+            return;
+        }
+        let answer = self.answer(actual);
+        let mut state = self.state();
+        if answer.is_none() {
+            state.unclocked.push_back(actual);
+        }
+        state.pending.push_back(answer);
+    }
+
+    /// Check a frame written against the script, and return its answer.
+    fn answer(&self, actual: u16) -> Option<Result<u16, Error>> {
+        let mut state = self.state();
+        let index = state.index;
+        state.index += 1;
+        let (expected, answer) = match state.script.pop_front() {
+            Some(Expectation::Transfer { write, read }) => (Some(write), Some(Ok(read))),
+            Some(Expectation::Error { write, error }) => (Some(write), Some(Err(error))),
+            Some(Expectation::Stall { write }) => (Some(write), None),
+            None => (None, Some(Ok(0))),
+        };
+        if expected != Some(actual) {
+            state.mismatches.push(Mismatch {
+                index,
+                expected,
+                actual,
+            });
+        }
+        answer
+    }
+}
+
+/// A `Delay` measured on the simulated clock of a `MockHardware`.
+pub struct MockDelay {
+    hardware: MockHardware,
+}
+
+/// Removes the deadline of a delay when it completes or is dropped.
+struct Deadline<'a> {
+    hardware: &'a MockHardware,
+    deadline: u64,
+}
+
+impl<'a> Drop for Deadline<'a> {
+    fn drop(&mut self) {
+        let mut state = self.hardware.state();
+        if let Some(i) = state.deadlines.iter().position(|&d| d == self.deadline) {
+            state.deadlines.swap_remove(i);
+        }
+    }
+}
+
+impl Delay for MockDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        let deadline = {
+            let mut state = self.hardware.state();
+            let deadline = state.now + u64::from(ns);
+            state.deadlines.push(deadline);
+            deadline
+        };
+        let deadline = Deadline {
+            hardware: &self.hardware,
+            deadline,
+        };
+        poll_fn(|_| {
+            if deadline.hardware.now() >= deadline.deadline {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "async-hal")]
+impl embedded_hal_async::delay::DelayNs for MockDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        Delay::delay_ns(self, ns).await
+    }
+}
+
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+impl SPIHardware for MockHardware {
+    fn read(&self) -> Result<Option<u8>, Error> {
+        self.take().map(|x| x.map(|x| x as u8))
+    }
+
+    fn write(&self, x: u8) {
+        self.put(u16::from(x));
+    }
+
+    fn read_u16(&self) -> Result<Option<u16>, Error> {
+        self.take()
+    }
+
+    fn write_u16(&self, x: u16) {
+        self.put(x);
+    }
+
+    fn set_frame_size(&mut self, bits: u8) {
+        self.state().frame_size = bits;
+    }
+
+    fn configure(&mut self, config: &Config) -> Result<u32, Error> {
+        // Pulses on NSS only fit between frames captured on the first edge.
+        if config.frame_format == FrameFormat::MotorolaPulsed
+            && config.mode.phase != Phase::CaptureOnFirstTransition
+        {
+            return Err(Error::Unsupported);
+        }
+        let mut state = self.state();
+        state.config = Some(*config);
+        state.configurations += 1;
+        Ok(config.frequency)
+    }
+
+    fn abort(&mut self) {
+        let mut state = self.state();
+        state.pending.clear();
+        state.unclocked.clear();
+        state.aborts += 1;
+    }
+
+    fn recover(&mut self) {
+        let mut state = self.state();
+        state.pending.clear();
+        state.unclocked.clear();
+        state.recoveries += 1;
+    }
+
+    fn start_dma(&mut self, block: &DmaBlock) -> usize {
+        if !self.state().dma {
+            return 0;
+        }
+        let size = if block.wide { 2 } else { 1 };
+        let mut outcome = Some(Ok(0));
+        for i in 0..block.len {
+            let tx = if block.tx_increment {
+                block.tx.wrapping_add(i * size)
+            } else {
+                block.tx
+            };
+            let rx = if block.rx_increment {
+                block.rx.wrapping_add(i * size)
+            } else {
+                block.rx
+            };
+            // NOTE(unsafe): The words of the block stay valid until it completes.
+            let x = unsafe {
+                if block.wide {
+                    tx.cast::<u16>().read_unaligned()
+                } else {
+                    u16::from(*tx)
+                }
+            };
+            match self.answer(x) {
+                // NOTE(unsafe): See above.
+                Some(Ok(y)) => unsafe {
+                    if block.wide {
+                        rx.cast::<u16>().write_unaligned(y);
+                    } else {
+                        *rx = y as u8;
+                    }
+                },
+                answer => {
+                    outcome = answer;
+                    break;
+                }
+            }
+        }
+        let mut state = self.state();
+        state.blocks += 1;
+        state.pending.push_back(outcome);
+        block.len
+    }
+
+    fn poll_dma(&mut self) -> Result<bool, Error> {
+        self.take().map(|x| x.is_some())
+    }
+
+    fn fifo_depth(&self, _wide: bool) -> usize {
+        self.state().depth
+    }
+
+    fn crc_next(&mut self) -> usize {
+        let mut state = self.state();
+        let index = state.index;
+        state.checksums.push(index);
+        for _ in 0..state.crc {
+            state.pending.push_back(Some(Ok(0)));
+        }
+        state.crc
+    }
+
+    fn check_crc(&mut self) -> Result<(), Error> {
+        let mut state = self.state();
+        if state.bad_crc {
+            state.bad_crc = false;
+            return Err(Error::BadChecksum);
+        }
+        Ok(())
+    }
+
+    fn half_duplex(&self) -> bool {
+        self.state().half_duplex
+    }
+
+    fn set_direction(&mut self, output: bool) -> bool {
+        let mut state = self.state();
+        let turned = state.output != output;
+        state.output = output;
+        turned
+    }
+}
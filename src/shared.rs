@@ -0,0 +1,195 @@
+//! A `SharedBus` allows several devices, each with their own chip select pin and configuration, to
+//! take turns using one `SPI`.
+use core::cell::{Cell, UnsafeCell};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{ErrorType, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi::SpiDevice;
+
+use crate::device::{transaction, DeviceError};
+use crate::{Config, SPIHardware, SPI};
+
+/// A `SPI` shared by at most `N` devices. Devices wait for each other asynchronously and get the
+/// bus in round-robin order, so no device can starve another.
+///
+/// The bus is not `Sync`: all devices must be used from tasks running on the same executor.
+pub struct SharedBus<H: 'static, const N: usize> {
+    spi: UnsafeCell<SPI<H>>,
+    /// The device that currently owns the bus.
+    owner: Cell<Option<usize>>,
+    /// The wakers of the devices waiting for the bus.
+    waiting: [Cell<Option<Waker>>; N],
+    devices: Cell<usize>,
+    /// The configuration last applied to the SPI.
+    config: Cell<Option<Config>>,
+}
+
+impl<H: SPIHardware, const N: usize> SharedBus<H, N> {
+    pub fn new(spi: SPI<H>) -> Self {
+        Self {
+            spi: UnsafeCell::new(spi),
+            owner: Cell::new(None),
+            waiting: [(); N].map(|_| Cell::new(None)),
+            devices: Cell::new(0),
+            config: Cell::new(None),
+        }
+    }
+
+    /// Create a new device on this bus, deasserting its chip select pin. The SPI is reconfigured
+    /// with `config` before each transaction on this device, unless it was already applied. The
+    /// fill byte of the device is zero until it is changed with `SharedDevice::set_fill`.
+    ///
+    /// Panics if the bus already has `N` devices.
+    pub fn device<CS: OutputPin, D: DelayNs>(
+        &self,
+        mut cs: CS,
+        delay: D,
+        config: Config,
+    ) -> Result<SharedDevice<'_, H, CS, D, N>, CS::Error> {
+        let id = self.devices.get();
+        assert!(id < N, "SharedBus::device called for more than N devices.");
+        cs.set_high()?;
+        self.devices.set(id + 1);
+        Ok(SharedDevice {
+            bus: self,
+            id,
+            cs,
+            delay,
+            config,
+            fill: 0,
+        })
+    }
+
+    fn lock(&self, id: usize) -> Lock<'_, H, N> {
+        Lock {
+            bus: self,
+            id,
+            locked: false,
+        }
+    }
+}
+
+impl<H, const N: usize> SharedBus<H, N> {
+    /// Hand the bus to the next waiting device after `id`, or leave it free if no device is
+    /// waiting.
+    fn unlock(&self, id: usize) {
+        for j in (id + 1..N).chain(0..=id) {
+            if let Some(waker) = self.waiting[j].take() {
+                self.owner.set(Some(j));
+                waker.wake();
+                return;
+            }
+        }
+        self.owner.set(None);
+    }
+}
+
+struct Lock<'a, H: 'static, const N: usize> {
+    bus: &'a SharedBus<H, N>,
+    id: usize,
+    locked: bool,
+}
+
+impl<'a, H: SPIHardware, const N: usize> Future for Lock<'a, H, N> {
+    type Output = Guard<'a, H, N>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let bus = self.bus;
+        match bus.owner.get() {
+            None => bus.owner.set(Some(self.id)),
+            Some(owner) if owner == self.id => {}
+            Some(_) => {
+                bus.waiting[self.id].set(Some(cx.waker().clone()));
+                return Poll::Pending;
+            }
+        }
+        self.locked = true;
+        Poll::Ready(Guard { bus, id: self.id })
+    }
+}
+
+impl<'a, H, const N: usize> Drop for Lock<'a, H, N> {
+    fn drop(&mut self) {
+        if self.locked {
+            return;
+        }
+        // Stop waiting, and pass the bus on if it was handed to us before we got to use it.
+        self.bus.waiting[self.id].set(None);
+        if self.bus.owner.get() == Some(self.id) {
+            self.bus.unlock(self.id);
+        }
+    }
+}
+
+struct Guard<'a, H: 'static, const N: usize> {
+    bus: &'a SharedBus<H, N>,
+    id: usize,
+}
+
+impl<'a, H, const N: usize> Guard<'a, H, N> {
+    fn spi(&mut self) -> &mut SPI<H> {
+        // NOTE(unsafe): The guard is the only owner of the bus, so no other reference to the SPI
+        // exists.
+        unsafe { &mut *self.bus.spi.get() }
+    }
+}
+
+impl<'a, H, const N: usize> Drop for Guard<'a, H, N> {
+    fn drop(&mut self) {
+        self.bus.unlock(self.id);
+    }
+}
+
+/// A device with an active low chip select pin on a `SharedBus`.
+pub struct SharedDevice<'a, H: 'static, CS, D, const N: usize> {
+    bus: &'a SharedBus<H, N>,
+    id: usize,
+    cs: CS,
+    delay: D,
+    config: Config,
+    /// The byte written during reads on this device.
+    fill: u8,
+}
+
+impl<'a, H, CS, D, const N: usize> SharedDevice<'a, H, CS, D, N> {
+    /// Change the configuration used for the following transactions.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Set the byte written during reads on this device, like `SPI::set_fill`, without affecting
+    /// the other devices on the bus.
+    pub fn set_fill(&mut self, fill: u8) {
+        self.fill = fill;
+    }
+}
+
+impl<'a, H: SPIHardware, CS: OutputPin, D, const N: usize> ErrorType
+    for SharedDevice<'a, H, CS, D, N>
+{
+    type Error = DeviceError<CS::Error>;
+}
+
+impl<'a, H: SPIHardware, CS: OutputPin, D: DelayNs, const N: usize> SpiDevice
+    for SharedDevice<'a, H, CS, D, N>
+{
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        let mut guard = self.bus.lock(self.id).await;
+        if self.bus.config.get() != Some(self.config) {
+            guard
+                .spi()
+                .configure(&self.config)
+                .map_err(DeviceError::SPI)?;
+            self.bus.config.set(Some(self.config));
+        }
+        guard.spi().set_fill(self.fill);
+        transaction(guard.spi(), &mut self.cs, &mut self.delay, operations).await
+    }
+}
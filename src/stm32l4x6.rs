@@ -1,92 +1,903 @@
-//! This is an example implementation for STM32L4x6.
-use stm32l4xx_hal::gpio::gpioa;
-use stm32l4xx_hal::{gpio, stm32};
+//! This is an example implementation for STM32L4x6. Every instance gets its own hardware:
+//! `SPI1Hardware`, `SPI2Hardware` and `SPI3Hardware`, generic over the pins it is given. The
+//! application declares a static handler for the hardware with its pins along with the interrupts
+//! forwarded to it with `spi_handler!`:
+//!
+//! ```ignore
+//! use async_spi::stm32l4x6::{SPI1Hardware, AF5};
+//! use stm32l4xx_hal::gpio::gpioa::{PA5, PA6, PA7};
+//!
+//! type Pins = (PA5<AF5>, PA6<AF5>, PA7<AF5>);
+//! spi_handler!(SPI1_HANDLER: SPI1Hardware<Pins>, SPI1);
+//! ```
+use core::cell::Cell;
 
-use stm32::interrupt;
+use stm32l4xx_hal::gpio::{gpioa, gpiob, gpioc, gpiod, gpioe};
+use stm32l4xx_hal::rcc::Clocks;
+use stm32l4xx_hal::{dma, gpio, stm32};
 
-use crate::{Error, SPIHandler, SPIHardware};
+use cortex_m::peripheral::NVIC;
+use stm32::Interrupt;
 
-type AF = gpio::Alternate<gpio::AF5, gpio::Input<gpio::Floating>>;
-type SCK = gpioa::PA5<AF>;
-type MISO = gpioa::PA6<AF>;
-type MOSI = gpioa::PA7<AF>;
+#[doc(hidden)]
+pub use stm32::interrupt as __interrupt;
 
-type Pins = (SCK, MISO, MOSI);
-type Regs = stm32::SPI1;
+use crate::{BitOrder, Config, DmaBlock, Error, FrameFormat, Phase, Polarity, SPIHardware};
 
-pub struct SPI1Hardware {
-    #[allow(unused)]
-    pins: Pins,
-    regs: Regs,
+/// The mode of the pins of SPI1 and SPI2.
+pub type AF5 = gpio::Alternate<gpio::AF5, gpio::Input<gpio::Floating>>;
+/// The mode of the pins of SPI3.
+pub type AF6 = gpio::Alternate<gpio::AF6, gpio::Input<gpio::Floating>>;
+
+/// A pin that can be used as SCK of the instance with registers `SPI`.
+pub trait SckPin<SPI> {}
+/// A pin that can be used as MISO of the instance with registers `SPI`.
+pub trait MisoPin<SPI> {}
+/// A pin that can be used as MOSI of the instance with registers `SPI`.
+pub trait MosiPin<SPI> {}
+/// A pin that can be used as NSS of the instance with registers `SPI`, as slave.
+pub trait NssPin<SPI> {}
+
+/// Takes the place of the MISO pin when nothing needs to be read, like for displays.
+pub struct NoMiso;
+/// Takes the place of the MOSI pin when nothing needs to be written.
+pub struct NoMosi;
+
+impl<SPI> MisoPin<SPI> for NoMiso {}
+impl<SPI> MosiPin<SPI> for NoMosi {}
+
+macro_rules! pins {
+    (
+        $SPIX:ident: $AF:ty,
+        sck: [$($gpio_sck:ident::$SCK:ident),*],
+        miso: [$($gpio_miso:ident::$MISO:ident),*],
+        mosi: [$($gpio_mosi:ident::$MOSI:ident),*],
+        nss: [$($gpio_nss:ident::$NSS:ident),*],
+    ) => {
+        $(impl SckPin<stm32::$SPIX> for $gpio_sck::$SCK<$AF> {})*
+        $(impl MisoPin<stm32::$SPIX> for $gpio_miso::$MISO<$AF> {})*
+        $(impl MosiPin<stm32::$SPIX> for $gpio_mosi::$MOSI<$AF> {})*
+        $(impl NssPin<stm32::$SPIX> for $gpio_nss::$NSS<$AF> {})*
+    };
 }
 
-pub static SPI1_HANDLER: SPIHandler<SPI1Hardware> = SPIHandler::new();
-
-impl SPI1Hardware {
-    pub fn new(pins: Pins, regs: Regs) -> Self {
-        regs.cr1.write(|w| unsafe {
-            w.br().bits(0b011); // f_PCLK / 16
-            w.cpol().clear_bit(); // CK to 0 when idle
-            w.cpha().set_bit(); // data capture on falling edges
-            w.mstr().set_bit(); // we are master
-            w.ssm().set_bit(); // software NSS management
-            w.ssi().set_bit(); // pretend NSS is always high so no other master is detected
-            w
-        });
-
-        regs.cr2.write(|w| unsafe {
-            w.ds().bits(0b0111); // 8-bit data transfer
-            w.frxth().set_bit(); // 8-bit fifo access
-            w.rxneie().set_bit(); // enable receive queue not empty interrupt
-            w.errie().set_bit(); // enable error interrupts
-            w
-        });
-
-        regs.cr1.modify(|_, w| w.spe().set_bit());
-
-        Self { pins, regs }
-    }
+pins! {
+    SPI1: AF5,
+    sck: [gpioa::PA5, gpiob::PB3, gpioe::PE13],
+    miso: [gpioa::PA6, gpiob::PB4, gpioe::PE14],
+    mosi: [gpioa::PA7, gpiob::PB5, gpioe::PE15],
+    nss: [gpioa::PA4, gpioa::PA15, gpiob::PB0, gpioe::PE12],
 }
 
-impl SPI1Hardware {
-    /// Accessing the data register through the register block causes 32-bit reads and writes which
-    /// are interpreted as two bytes by the peripheral. This pointer will access single bytes
-    /// instead.
-    const DR: *mut u8 = 0x4001300c as *mut u8;
-
-    fn status(&self) -> Result<stm32::spi1::sr::R, Error> {
-        use Error::*;
-        let sr = self.regs.sr.read();
-        if sr.tifrfe().bit() {
-            Err(BadFrameFormat)
-        } else if sr.ovr().bit() {
-            Err(Overrun)
-        } else if sr.modf().bit() {
-            Err(ModeFault)
-        } else if sr.crcerr().bit() {
-            Err(BadChecksum)
-        } else {
-            Ok(sr)
-        }
-    }
+pins! {
+    SPI2: AF5,
+    sck: [gpiob::PB10, gpiob::PB13, gpiod::PD1],
+    miso: [gpiob::PB14, gpioc::PC2, gpiod::PD3],
+    mosi: [gpiob::PB15, gpioc::PC3, gpiod::PD4],
+    nss: [gpiob::PB9, gpiob::PB12, gpiod::PD0],
 }
 
-impl SPIHardware for SPI1Hardware {
-    fn write(&self, x: u8) {
-        unsafe { Self::DR.write_volatile(x) }
-    }
+pins! {
+    SPI3: AF6,
+    sck: [gpiob::PB3, gpioc::PC10],
+    miso: [gpiob::PB4, gpioc::PC11],
+    mosi: [gpiob::PB5, gpioc::PC12],
+    nss: [gpioa::PA4, gpioa::PA15],
+}
+
+/// The baud rate control bits that divide `pclk` into the highest SPI clock not exceeding
+/// `frequency`, which is f_PCLK / 2^(br + 1). Returns `None` if even the slowest clock is too fast.
+fn prescaler(pclk: u32, frequency: u32) -> Option<u8> {
+    (0..8).find(|br| frequency > 0 && pclk >> (br + 1) <= frequency)
+}
+
+/// Declare the hardware of an instance. The DMA channels and request are those of the instance in
+/// the DMA request mapping of the reference manual.
+macro_rules! spi {
+    (
+        $SPIX:ident: ($module:ident, $Hardware:ident),
+        clock: ($pclk:ident, $rstr:ident, $rst:ident),
+        dma: ($DMA:ident, $dmaX:ident::{$CRX:ident, $CTX:ident}, ($DMA_RX:ident, $DMA_TX:ident),
+              $request:expr),
+        rx: ($ccr_rx:ident, $cpar_rx:ident, $cmar_rx:ident, $cndtr_rx:ident, $cs_rx:ident,
+             $tcif_rx:ident, $teif_rx:ident, $cgif_rx:ident),
+        tx: ($ccr_tx:ident, $cpar_tx:ident, $cmar_tx:ident, $cndtr_tx:ident, $cs_tx:ident,
+             $teif_tx:ident, $cgif_tx:ident),
+    ) => {
+        mod $module {
+            use super::*;
+
+            type Regs = stm32::$SPIX;
+
+            pub struct $Hardware<PINS> {
+                /// Whether we are slave, selected by the master through the NSS pin.
+                slave: bool,
+                /// Whether NSS is driven by the peripheral as master.
+                nss_output: bool,
+                /// Whether the data is moved over a single, bidirectional line.
+                half_duplex: bool,
+                /// Whether the half-duplex data line is an output.
+                output: bool,
+                /// The number of frames written on the half-duplex data line that were not read.
+                pending: Cell<usize>,
+                /// The pins given to the constructor, given back by `free`.
+                pins: PINS,
+                regs: Regs,
+                /// The frequency of the peripheral clock, from which the SPI clock is derived.
+                pclk: u32,
+                /// The frequency of the processor clock.
+                hclk: u32,
+                /// When set, blocks of words are moved by the receive and transmit DMA channels.
+                dma: Option<(dma::$dmaX::$CRX, dma::$dmaX::$CTX)>,
+                /// Whether several frames are queued in the fifos at once.
+                burst: bool,
+                /// The length in bits of the checksum appended to every transfer, if any.
+                crc: Option<u8>,
+            }
+
+            impl<SCK, MISO, MOSI> $Hardware<(SCK, MISO, MOSI)>
+            where
+                SCK: SckPin<Regs>,
+                MISO: MisoPin<Regs>,
+                MOSI: MosiPin<Regs>,
+            {
+                /// Act as master on the given SCK, MISO and MOSI pins. Use `NoMiso` when nothing is
+                /// read or `NoMosi` when nothing is written. Reads then return whatever the
+                /// floating MISO input sees, and writes only generate the clock.
+                pub fn new(pins: (SCK, MISO, MOSI), regs: Regs, clocks: &Clocks) -> Self {
+                    Self::setup(false, pins, regs, clocks)
+                }
+            }
+
+            impl<SCK, MISO, MOSI, NSS> $Hardware<((SCK, MISO, MOSI), NSS)>
+            where
+                SCK: SckPin<Regs>,
+                MISO: MisoPin<Regs>,
+                MOSI: MosiPin<Regs>,
+                NSS: NssPin<Regs>,
+            {
+                /// Act as master, with NSS driven by the peripheral, which is needed for the TI
+                /// frame format and for pulses on NSS between frames. With Motorola frames, NSS is
+                /// low as long as the peripheral is enabled.
+                pub fn new_with_nss(
+                    pins: (SCK, MISO, MOSI),
+                    nss: NSS,
+                    regs: Regs,
+                    clocks: &Clocks,
+                ) -> Self {
+                    let mut hardware = Self::setup(false, (pins, nss), regs, clocks);
+                    hardware.while_disabled(|regs| {
+                        regs.cr1.modify(|_, w| w.ssm().clear_bit()); // hardware NSS management
+                        regs.cr2.modify(|_, w| w.ssoe().set_bit()); // drive NSS as output
+                    });
+                    hardware.nss_output = true;
+                    hardware
+                }
+
+                /// Act as slave, selected by the master through the NSS pin. To complete
+                /// `SPI::respond`, an EXTI interrupt on the rising edge of the NSS pin must
+                /// call `handle_deselect()` on the handler of this instance.
+                pub fn new_slave(
+                    pins: (SCK, MISO, MOSI),
+                    nss: NSS,
+                    regs: Regs,
+                    clocks: &Clocks,
+                ) -> Self {
+                    Self::setup(true, (pins, nss), regs, clocks)
+                }
+            }
+
+            impl<SCK, MOSI> $Hardware<(SCK, MOSI)>
+            where
+                SCK: SckPin<Regs>,
+                MOSI: MosiPin<Regs>,
+            {
+                /// Act as master on a single, bidirectional data line connected to the MOSI pin.
+                /// The direction of the line follows the operations: it is an output for
+                /// operations that only write and an input for operations that only read.
+                /// Operations that both write and read fail with `Error::Unsupported`. See also
+                /// `SPI::set_turnaround`.
+                pub fn new_half_duplex(pins: (SCK, MOSI), regs: Regs, clocks: &Clocks) -> Self {
+                    let mut hardware = Self::setup(false, pins, regs, clocks);
+                    hardware.while_disabled(|regs| {
+                        regs.cr1.modify(|_, w| {
+                            w.bidimode().set_bit(); // a single bidirectional data line
+                            w.bidioe().set_bit(); // which is an output
+                            w
+                        })
+                    });
+                    hardware.half_duplex = true;
+                    hardware
+                }
+            }
+
+            impl<PINS> $Hardware<PINS> {
+                /// Disable the peripheral and give back the registers, the receive and transmit
+                /// DMA channels and the pins, for example after `SPI::release`. The pins are those
+                /// given to `new` or `new_half_duplex`, or `(pins, nss)` given to `new_slave` or
+                /// `new_with_nss`.
+                pub fn free(
+                    mut self,
+                ) -> (Regs, Option<(dma::$dmaX::$CRX, dma::$dmaX::$CTX)>, PINS) {
+                    self.disable();
+                    (self.regs, self.dma, self.pins)
+                }
+
+                fn setup(slave: bool, pins: PINS, regs: Regs, clocks: &Clocks) -> Self {
+                    let master = !slave;
+                    regs.cr1.write(|w| unsafe {
+                        w.br().bits(0b011); // f_PCLK / 16
+                        w.cpol().clear_bit(); // CK to 0 when idle
+                        w.cpha().set_bit(); // data capture on falling edges
+                        w.mstr().bit(master); // we are master, or slave selected by the NSS pin
+                        w.ssm().bit(master); // software NSS management as master
+                        w.ssi().bit(master); // pretend NSS is high so no other master is detected
+                        w
+                    });
+
+                    // As slave, the interrupts are enabled by the first frame written.
+                    regs.cr2.write(|w| unsafe {
+                        w.ds().bits(0b0111); // 8-bit data transfer
+                        w.frxth().set_bit(); // 8-bit fifo access
+                        w.rxneie().bit(master); // enable receive queue not empty interrupt
+                        w.errie().bit(master); // enable error interrupts
+                        w
+                    });
+
+                    regs.cr1.modify(|_, w| w.spe().set_bit());
+
+                    Self {
+                        slave,
+                        nss_output: false,
+                        half_duplex: false,
+                        output: true,
+                        pending: Cell::new(0),
+                        pins,
+                        regs,
+                        pclk: clocks.$pclk().0,
+                        hclk: clocks.hclk().0,
+                        dma: None,
+                        burst: false,
+                        crc: None,
+                    }
+                }
+
+                /// Move words using DMA instead of handling an interrupt for every frame, on the
+                /// receive and transmit channels of this instance obtained with `DmaExt::split`,
+                /// which also enables the DMA clock. The interrupts of both channels must be
+                /// unmasked and forwarded to the handler of this instance with `spi_handler!`: the
+                /// receive channel signals the end of a block, the transmit channel an error, which
+                /// stops it before the receive channel completes. They must have the same
+                /// priority as the SPI interrupt, because all of them call `handle_interrupt`,
+                /// which must not preempt itself.
+                pub fn with_dma(mut self, rx: dma::$dmaX::$CRX, tx: dma::$dmaX::$CTX) -> Self {
+                    // Select the receive and transmit requests of this instance. The register is
+                    // shared with the other channels of the controller.
+                    cortex_m::interrupt::free(|_| {
+                        Self::dma_regs().cselr.modify(|_, w| {
+                            w.$cs_rx().bits($request);
+                            w.$cs_tx().bits($request);
+                            w
+                        })
+                    });
+                    self.dma = Some((rx, tx));
+                    self
+                }
+
+                /// Keep as many frames queued as the fifos hold, instead of writing a frame only
+                /// after the previous one was read. This removes the gaps between frames, and the
+                /// interrupt handler reads every frame that arrived in the meantime.
+                pub fn with_burst(mut self) -> Self {
+                    self.burst = true;
+                    self
+                }
+
+                /// Append a checksum of `bits` bits, 8 or 16, to every transfer, and check the
+                /// checksum received after it. The checksum is calculated by the peripheral using
+                /// `polynomial`, without its highest bit. A mismatch fails the transfer with
+                /// `Error::BadChecksum`. Words are not moved by DMA while this is enabled.
+                pub fn with_crc(mut self, polynomial: u16, bits: u8) -> Self {
+                    assert!(
+                        bits == 8 || bits == 16,
+                        "with_crc called with a checksum length other than 8 or 16 bits."
+                    );
+                    self.while_disabled(|regs| {
+                        regs.crcpr.write(|w| unsafe { w.crcpoly().bits(polynomial) });
+                        regs.cr1.modify(|_, w| {
+                            // On the L4 this bit is CRCL, the CRC length, which the PAC still
+                            // calls DFF after the data frame format bit of older parts.
+                            w.dff().bit(bits == 16);
+                            w.crcen().set_bit();
+                            w
+                        });
+                    });
+                    self.crc = Some(bits);
+                    self
+                }
+
+                /// Restart the calculation of the checksum for the next transfer.
+                fn restart_crc(&self) {
+                    if self.crc.is_some() {
+                        self.while_disabled(|regs| {
+                            regs.cr1.modify(|_, w| w.crcen().clear_bit());
+                            regs.cr1.modify(|_, w| w.crcen().set_bit());
+                        });
+                    }
+                }
+
+                /// Wait for the transmission to finish, then run `f` with the peripheral disabled,
+                /// following the procedure from the reference manual. The receive fifo is already
+                /// empty between transfers. The peripheral is enabled again afterwards, unless it
+                /// was disabled to stop reading the half-duplex data line.
+                fn while_disabled(&self, f: impl FnOnce(&Regs)) {
+                    self.finish_transmission();
+                    let enabled = self.regs.cr1.read().spe().bit();
+                    self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+                    f(&self.regs);
+                    self.regs.cr1.modify(|_, w| w.spe().bit(enabled));
+                }
+            }
+
+            impl<PINS> $Hardware<PINS> {
+                /// Accessing the data register through the register block causes 32-bit reads and
+                /// writes which are interpreted as two bytes by the peripheral. This pointer will
+                /// access single bytes, or half-words for `T = u16`, instead.
+                fn dr<T>(&self) -> *mut T {
+                    &self.regs.dr as *const _ as *mut T
+                }
+
+                fn status(&self) -> Result<stm32::spi1::sr::R, Error> {
+                    use Error::*;
+                    let sr = self.regs.sr.read();
+                    if sr.tifrfe().bit() {
+                        Err(BadFrameFormat)
+                    } else if sr.ovr().bit() {
+                        Err(Overrun)
+                    } else if sr.modf().bit() {
+                        Err(ModeFault)
+                    } else if sr.crcerr().bit() {
+                        Err(BadChecksum)
+                    } else {
+                        Ok(sr)
+                    }
+                }
+
+                /// Read a frame if one has been received. On the half-duplex data line, a frame
+                /// written counts as read once it has left the transmit fifo.
+                fn read_frame<T: Default>(&self) -> Result<Option<T>, Error> {
+                    let sr = self.status()?;
+                    if !self.half_duplex {
+                        return Ok(if sr.rxne().bit() {
+                            Some(unsafe { self.dr::<T>().read_volatile() })
+                        } else {
+                            None
+                        });
+                    }
+                    let pending = self.pending.get();
+                    if pending == 0 {
+                        return Ok(None);
+                    }
+                    let x = if self.output {
+                        if !sr.txe().bit() {
+                            return Ok(None);
+                        }
+                        self.regs.cr2.modify(|_, w| w.txeie().clear_bit());
+                        T::default()
+                    } else {
+                        if !sr.rxne().bit() {
+                            return Ok(None);
+                        }
+                        unsafe { self.dr::<T>().read_volatile() }
+                    };
+                    self.pending.set(pending - 1);
+                    Ok(Some(x))
+                }
+
+                /// Write a frame. On the half-duplex data line, the transmit fifo empty interrupt
+                /// takes the place of the receive fifo not empty interrupt while writing, and a
+                /// single frame is clocked in instead while reading.
+                fn write_frame<T>(&self, x: T) {
+                    if self.slave {
+                        self.listen();
+                    }
+                    if self.half_duplex {
+                        self.pending.set(self.pending.get() + 1);
+                        if !self.output {
+                            // Frames are clocked in for as long as the peripheral is enabled. As
+                            // in the reference manual, it is disabled one clock cycle into the
+                            // frame, which is then completed, so no further frame is clocked in.
+                            let cycles = self.clock_cycle();
+                            cortex_m::interrupt::free(|_| {
+                                self.regs.cr1.modify(|_, w| w.spe().set_bit());
+                                cortex_m::asm::delay(cycles);
+                                self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+                            });
+                            return;
+                        }
+                    }
+                    unsafe { self.dr::<T>().write_volatile(x) };
+                    if self.half_duplex {
+                        self.regs.cr2.modify(|_, w| w.txeie().set_bit());
+                    }
+                }
+
+                /// As slave, the master may clock frames at any time, so the interrupts are only
+                /// enabled while `SPI::respond` is in progress: from the first frame written until
+                /// the transfer is aborted or fails. The frames clocked in before are discarded.
+                fn listen(&self) {
+                    if self.regs.cr2.read().rxneie().bit() {
+                        return;
+                    }
+                    // An overrun is cleared by reading the data register and then the status
+                    // register.
+                    self.drain();
+                    let _ = self.regs.sr.read();
+                    self.regs.cr2.modify(|_, w| {
+                        w.rxneie().set_bit();
+                        w.errie().set_bit();
+                        w
+                    });
+                }
+
+                /// Wait for the frames in the transmit fifo to be sent. As slave, the fifo is empty
+                /// between transfers, because the frames queued for the master are discarded when
+                /// a transfer is aborted or fails, and it would only empty when the master clocks
+                /// it out.
+                fn finish_transmission(&self) {
+                    if !self.slave {
+                        while self.regs.sr.read().ftlvl().bits() != 0 {}
+                    }
+                    while self.regs.sr.read().bsy().bit() {}
+                }
+
+                /// The number of processor cycles in a cycle of the SPI clock.
+                fn clock_cycle(&self) -> u32 {
+                    let br = u32::from(self.regs.cr1.read().br().bits());
+                    (self.hclk / self.pclk).max(1) << (br + 1)
+                }
+
+                /// Stop clocking frames in on the half-duplex data line, and discard the frames
+                /// received in the meantime.
+                fn stop_input(&self) {
+                    self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+                    while self.regs.sr.read().bsy().bit() {}
+                    self.drain();
+                    NVIC::unpend(Interrupt::$SPIX);
+                }
+            }
+
+            impl<PINS> $Hardware<PINS> {
+                /// The registers of the DMA controller. Only those of the channels of this
+                /// instance are written, and of the shared registers only their bits.
+                fn dma_regs() -> &'static stm32::dma1::RegisterBlock {
+                    // NOTE(unsafe): The channels are owned by the hardware while it uses them.
+                    unsafe { &*stm32::$DMA::ptr() }
+                }
+
+                /// The registers of the DMA controller, if the hardware owns its channels.
+                fn dma(&self) -> Option<&'static stm32::dma1::RegisterBlock> {
+                    self.dma.as_ref().map(|_| Self::dma_regs())
+                }
 
-    fn read(&self) -> Result<Option<u8>, Error> {
-        if self.status()?.rxne().bit() {
-            Ok(Some(unsafe { Self::DR.read_volatile() }))
-        } else {
-            Ok(None)
+                fn stop_dma(&self, dma: &stm32::dma1::RegisterBlock) {
+                    dma.$ccr_rx.modify(|_, w| w.en().clear_bit());
+                    dma.$ccr_tx.modify(|_, w| w.en().clear_bit());
+                    dma.ifcr.write(|w| w.$cgif_rx().set_bit().$cgif_tx().set_bit());
+                    self.regs.cr2.modify(|_, w| {
+                        w.txdmaen().clear_bit();
+                        w.rxdmaen().clear_bit();
+                        w.rxneie().set_bit();
+                        w
+                    });
+                }
+
+                /// Reset the peripheral while keeping its configuration. As slave, this is the only
+                /// way to discard the words in the transmit fifo.
+                fn reset(&self) {
+                    let cr1 = self.regs.cr1.read().bits();
+                    let cr2 = self.regs.cr2.read().bits();
+                    let crcpr = self.regs.crcpr.read().bits();
+                    // NOTE(unsafe): Only the reset bit of this instance is touched, which is owned
+                    // by us.
+                    let rcc = unsafe { &*stm32::RCC::ptr() };
+                    rcc.$rstr.modify(|_, w| w.$rst().set_bit());
+                    rcc.$rstr.modify(|_, w| w.$rst().clear_bit());
+                    self.regs.crcpr.write(|w| unsafe { w.bits(crcpr) });
+                    self.regs.cr2.write(|w| unsafe { w.bits(cr2) });
+                    self.regs.cr1.write(|w| unsafe { w.bits(cr1) });
+                }
+
+                /// Read and discard everything in the receive fifo.
+                fn drain(&self) {
+                    while self.regs.sr.read().frlvl().bits() != 0 {
+                        unsafe { self.dr::<u8>().read_volatile() };
+                    }
+                }
+            }
+
+            impl<PINS> SPIHardware for $Hardware<PINS> {
+                fn write(&self, x: u8) {
+                    self.write_frame(x)
+                }
+
+                fn read(&self) -> Result<Option<u8>, Error> {
+                    self.read_frame()
+                }
+
+                fn write_u16(&self, x: u16) {
+                    self.write_frame(x)
+                }
+
+                fn read_u16(&self) -> Result<Option<u16>, Error> {
+                    self.read_frame()
+                }
+
+                fn set_frame_size(&mut self, bits: u8) {
+                    self.while_disabled(|regs| {
+                        regs.cr2.modify(|_, w| unsafe {
+                            w.ds().bits(bits - 1);
+                            // RXNE is set once a full frame is in the fifo.
+                            w.frxth().bit(bits <= 8);
+                            w
+                        })
+                    });
+                }
+
+                fn configure(&mut self, config: &Config) -> Result<u32, Error> {
+                    let format = config.frame_format;
+                    // Frame formats other than Motorola need NSS driven by the peripheral, which
+                    // needs `new_with_nss`, and pulses only fit between frames captured on the
+                    // first edge.
+                    let nss = self.slave || self.nss_output;
+                    let pulsed = format == FrameFormat::MotorolaPulsed;
+                    if (!nss && format != FrameFormat::Motorola)
+                        || (pulsed && config.mode.phase != Phase::CaptureOnFirstTransition)
+                    {
+                        return Err(Error::Unsupported);
+                    }
+                    let br = prescaler(self.pclk, config.frequency).ok_or(Error::Unsupported)?;
+                    self.while_disabled(|regs| {
+                        regs.cr1.modify(|_, w| unsafe {
+                            w.br().bits(br);
+                            w.cpol().bit(config.mode.polarity == Polarity::IdleHigh);
+                            w.cpha()
+                                .bit(config.mode.phase == Phase::CaptureOnSecondTransition);
+                            w.lsbfirst().bit(config.bit_order == BitOrder::LsbFirst);
+                            w
+                        });
+                        regs.cr2.modify(|_, w| {
+                            w.frf().bit(format == FrameFormat::TI);
+                            w.nssp().bit(format == FrameFormat::MotorolaPulsed);
+                            w
+                        });
+                    });
+                    Ok(self.pclk >> (br + 1))
+                }
+
+                fn start_dma(&mut self, block: &DmaBlock) -> usize {
+                    // The peripheral would append a checksum to every block instead of to the
+                    // transfer, and the half-duplex data line needs the frames to be counted.
+                    let dma = match self.dma() {
+                        Some(dma) if self.crc.is_none() && !self.half_duplex => dma,
+                        _ => return 0,
+                    };
+                    let len = block.len.min(u16::MAX as usize);
+                    let size = if block.wide { 0b01 } else { 0b00 };
+                    // The receive channel interrupt replaces the receive queue not empty interrupt.
+                    // The order of enabling the DMA requests and channels follows the reference
+                    // manual.
+                    self.regs.cr2.modify(|_, w| {
+                        w.rxneie().clear_bit();
+                        w.rxdmaen().set_bit();
+                        w
+                    });
+                    dma.$cpar_rx.write(|w| unsafe { w.pa().bits(self.dr::<u8>() as u32) });
+                    dma.$cmar_rx.write(|w| unsafe { w.ma().bits(block.rx as u32) });
+                    dma.$cndtr_rx.write(|w| w.ndt().bits(len as u16));
+                    dma.$ccr_rx.write(|w| unsafe {
+                        w.psize().bits(size);
+                        w.msize().bits(size);
+                        w.minc().bit(block.rx_increment);
+                        w.dir().clear_bit(); // read from the peripheral
+                        w.tcie().set_bit();
+                        w.teie().set_bit();
+                        w.en().set_bit();
+                        w
+                    });
+                    dma.$cpar_tx.write(|w| unsafe { w.pa().bits(self.dr::<u8>() as u32) });
+                    dma.$cmar_tx.write(|w| unsafe { w.ma().bits(block.tx as u32) });
+                    dma.$cndtr_tx.write(|w| w.ndt().bits(len as u16));
+                    dma.$ccr_tx.write(|w| unsafe {
+                        w.psize().bits(size);
+                        w.msize().bits(size);
+                        w.minc().bit(block.tx_increment);
+                        w.dir().set_bit(); // read from memory
+                        w.teie().set_bit();
+                        w.en().set_bit();
+                        w
+                    });
+                    self.regs.cr2.modify(|_, w| w.txdmaen().set_bit());
+                    len
+                }
+
+                fn abort(&mut self) {
+                    // The interrupt handler may modify the same registers, so it is kept from
+                    // running until none of its interrupts can trigger anymore.
+                    cortex_m::interrupt::free(|_| {
+                        self.regs.cr2.modify(|_, w| {
+                            w.rxneie().clear_bit();
+                            w.txeie().clear_bit();
+                            w.errie().clear_bit();
+                            w
+                        });
+                        if let Some(dma) = self.dma() {
+                            dma.$ccr_rx.modify(|_, w| w.en().clear_bit());
+                            dma.$ccr_tx.modify(|_, w| w.en().clear_bit());
+                        }
+                    });
+                    self.pending.set(0);
+                    if !self.output {
+                        self.stop_input();
+                    }
+                    if self.slave {
+                        // As slave, the transmit fifo won't empty until the master clocks it out.
+                        self.reset();
+                    } else {
+                        while self.regs.sr.read().ftlvl().bits() != 0 {}
+                        while self.regs.sr.read().bsy().bit() {}
+                        self.drain();
+                    }
+                    if let Some(dma) = self.dma() {
+                        self.stop_dma(dma);
+                        NVIC::unpend(Interrupt::$DMA_RX);
+                        NVIC::unpend(Interrupt::$DMA_TX);
+                    }
+                    // Clear the error flags the discarded frames may have caused, then any
+                    // interrupt that was triggered before the interrupts were disabled.
+                    let _ = self.regs.sr.read();
+                    NVIC::unpend(Interrupt::$SPIX);
+                    let master = !self.slave;
+                    self.regs.cr2.modify(|_, w| {
+                        w.rxneie().bit(master);
+                        w.errie().bit(master);
+                        w
+                    });
+                }
+
+                fn recover(&mut self) {
+                    if self.burst || self.slave {
+                        // Only a reset discards the frames still queued in the transmit fifo,
+                        // which as slave holds the next frame for the master. It clears the error
+                        // flags as well.
+                        self.reset();
+                    } else {
+                        // An overrun is cleared by reading the data register and then the status
+                        // register, a mode fault by reading the status register and then writing
+                        // the control register. Frame format errors are cleared by reading the
+                        // status register, checksum errors by writing the flag.
+                        self.drain();
+                        if self.regs.sr.read().crcerr().bit() {
+                            self.regs.sr.modify(|_, w| w.crcerr().clear_bit());
+                        }
+                    }
+                    // A mode fault clears MSTR and SPE. Reading the half-duplex data line only
+                    // enables the peripheral while frames are clocked in.
+                    let master = !self.slave;
+                    let enable = self.output;
+                    self.regs
+                        .cr1
+                        .modify(|_, w| w.mstr().bit(master).spe().bit(enable));
+                    // As slave, the transfer is over until the next frame is written.
+                    self.regs.cr2.modify(|_, w| {
+                        w.txeie().clear_bit();
+                        w.rxneie().bit(master);
+                        w.errie().bit(master);
+                        w
+                    });
+                    self.pending.set(0);
+                    self.restart_crc();
+                }
+
+                fn crc_next(&mut self) -> usize {
+                    let bits = match self.crc {
+                        Some(bits) => bits,
+                        None => return 0,
+                    };
+                    self.regs.cr1.modify(|_, w| w.crcnext().set_bit());
+                    // The checksum is received into the fifo like frames of the data size.
+                    let wide = self.regs.cr2.read().ds().bits() > 0b0111;
+                    if wide {
+                        1
+                    } else {
+                        usize::from(bits / 8)
+                    }
+                }
+
+                fn check_crc(&mut self) -> Result<(), Error> {
+                    if self.crc.is_none() {
+                        return Ok(());
+                    }
+                    let result = if self.regs.sr.read().crcerr().bit() {
+                        self.regs.sr.modify(|_, w| w.crcerr().clear_bit());
+                        Err(Error::BadChecksum)
+                    } else {
+                        Ok(())
+                    };
+                    self.restart_crc();
+                    result
+                }
+
+                fn enable(&mut self) {
+                    let master = !self.slave;
+                    self.regs.cr2.modify(|_, w| {
+                        w.rxneie().bit(master);
+                        w.errie().bit(master);
+                        w
+                    });
+                    // Reading the half-duplex data line only enables the peripheral while frames
+                    // are clocked in, and it may have been released as an input.
+                    let enable = self.output;
+                    self.regs.cr1.modify(|_, w| w.spe().bit(enable));
+                }
+
+                fn disable(&mut self) {
+                    self.finish_transmission();
+                    self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+                    self.regs.cr2.modify(|_, w| {
+                        w.rxneie().clear_bit();
+                        w.errie().clear_bit();
+                        w
+                    });
+                    NVIC::unpend(Interrupt::$SPIX);
+                }
+
+                fn half_duplex(&self) -> bool {
+                    self.half_duplex
+                }
+
+                fn set_direction(&mut self, output: bool) -> bool {
+                    // Every frame has been clocked in or has left the transmit fifo, so only the
+                    // transmission has to be finished. The peripheral is only enabled while
+                    // writing, and one frame at a time while reading.
+                    if output == self.output {
+                        return false;
+                    }
+                    self.flush();
+                    self.regs
+                        .cr1
+                        .modify(|_, w| w.bidioe().bit(output).spe().bit(output));
+                    self.output = output;
+                    true
+                }
+
+                fn flush(&mut self) {
+                    if self.half_duplex && self.output {
+                        while self.regs.sr.read().ftlvl().bits() != 0 {}
+                        while self.regs.sr.read().bsy().bit() {}
+                    }
+                }
+
+                fn fifo_depth(&self, wide: bool) -> usize {
+                    // Frames on the half-duplex data line are moved one at a time: a frame written
+                    // is only counted once it has left the transmit fifo, and frames are clocked
+                    // in one at a time.
+                    if self.half_duplex {
+                        return 1;
+                    }
+                    // Both fifos hold 32 bits.
+                    match (self.burst, wide) {
+                        (false, _) => 1,
+                        (true, false) => 4,
+                        (true, true) => 2,
+                    }
+                }
+
+                fn poll_dma(&mut self) -> Result<bool, Error> {
+                    let dma = self.dma().unwrap();
+                    let isr = dma.isr.read();
+                    let result = if isr.$teif_rx().bit() || isr.$teif_tx().bit() {
+                        Err(Error::Dma)
+                    } else {
+                        // Every word is received after it has been sent, so the block is done once
+                        // the receive channel is done.
+                        self.status().map(|_| isr.$tcif_rx().bit())
+                    };
+                    if !matches!(result, Ok(false)) {
+                        self.stop_dma(dma);
+                    }
+                    result
+                }
+            }
         }
-    }
+
+        pub use $module::$Hardware;
+    };
+}
+
+spi! {
+    SPI1: (spi1, SPI1Hardware),
+    clock: (pclk2, apb2rstr, spi1rst),
+    dma: (DMA1, dma1::{C2, C3}, (DMA1_CH2, DMA1_CH3), 0b0001),
+    rx: (ccr2, cpar2, cmar2, cndtr2, c2s, tcif2, teif2, cgif2),
+    tx: (ccr3, cpar3, cmar3, cndtr3, c3s, teif3, cgif3),
+}
+
+spi! {
+    SPI2: (spi2, SPI2Hardware),
+    clock: (pclk1, apb1rstr1, spi2rst),
+    dma: (DMA1, dma1::{C4, C5}, (DMA1_CH4, DMA1_CH5), 0b0001),
+    rx: (ccr4, cpar4, cmar4, cndtr4, c4s, tcif4, teif4, cgif4),
+    tx: (ccr5, cpar5, cmar5, cndtr5, c5s, teif5, cgif5),
+}
+
+spi! {
+    SPI3: (spi3, SPI3Hardware),
+    clock: (pclk1, apb1rstr1, spi3rst),
+    dma: (DMA2, dma2::{C1, C2}, (DMA2_CH1, DMA2_CH2), 0b0011),
+    rx: (ccr1, cpar1, cmar1, cndtr1, c1s, tcif1, teif1, cgif1),
+    tx: (ccr2, cpar2, cmar2, cndtr2, c2s, teif2, cgif2),
 }
 
-#[interrupt]
-fn SPI1() {
-    // NOTE(unsafe): Must be and is called in the interrupt handler.
-    unsafe { SPI1_HANDLER.handle_interrupt() };
+/// Define an interrupt, forwarding it to the handler of a SPI instance. This is needed for the SPI
+/// interrupt of every instance, and for the receive and transmit DMA channels of every instance
+/// whose hardware is given DMA with `with_dma`: `DMA1_CH2` and `DMA1_CH3` for SPI1, `DMA1_CH4` and
+/// `DMA1_CH5` for SPI2, and `DMA2_CH1` and `DMA2_CH2` for SPI3. An application that uses the
+/// channels for something else should not call `with_dma` and can define the interrupts itself.
+/// The priority of the DMA interrupts must be the same as that of the SPI interrupt.
+///
+/// ```ignore
+/// spi_interrupt!(SPI1 => SPI1_HANDLER);
+/// spi_interrupt!(DMA1_CH2 => SPI1_HANDLER);
+/// spi_interrupt!(DMA1_CH3 => SPI1_HANDLER);
+/// ```
+#[macro_export]
+macro_rules! spi_interrupt {
+    ($INTERRUPT:ident => $HANDLER:path) => {
+        const _: () = {
+            use $crate::stm32l4x6::__interrupt as interrupt;
+
+            #[interrupt]
+            fn $INTERRUPT() {
+                // NOTE(unsafe): Must be and is called in the interrupt handler. The DMA interrupt
+                // takes over from the SPI interrupt while a block is moved.
+                unsafe { $HANDLER.handle_interrupt() };
+            }
+        };
+    };
+}
+
+/// Declare a static handler for the hardware of a SPI instance, and define the interrupts that are
+/// forwarded to it with `spi_interrupt!`: the SPI interrupt of the instance, followed by the
+/// receive and transmit DMA channels if the hardware is given DMA with `with_dma`.
+///
+/// ```ignore
+/// spi_handler!(SPI1_HANDLER: SPI1Hardware<Pins>, SPI1);
+/// spi_handler!(pub SPI2_HANDLER: SPI2Hardware<Pins>, SPI2, DMA1_CH4, DMA1_CH5);
+/// ```
+#[macro_export]
+macro_rules! spi_handler {
+    ($vis:vis $HANDLER:ident: $Hardware:ty, $($INTERRUPT:ident),+ $(,)?) => {
+        $vis static $HANDLER: $crate::SPIHandler<$Hardware> = $crate::SPIHandler::new();
+        $($crate::spi_interrupt!($INTERRUPT => $HANDLER);)+
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescaler_does_not_exceed_frequency() {
+        assert_eq!(prescaler(80_000_000, 50_000_000), Some(0));
+        assert_eq!(prescaler(80_000_000, 10_000_000), Some(2));
+        assert_eq!(prescaler(80_000_000, 9_999_999), Some(3));
+        assert_eq!(prescaler(80_000_000, 312_500), Some(7));
+    }
+
+    #[test]
+    fn prescaler_rejects_slow_frequency() {
+        assert_eq!(prescaler(80_000_000, 312_499), None);
+        assert_eq!(prescaler(80_000_000, 100_000), None);
+        assert_eq!(prescaler(80_000_000, 0), None);
+    }
 }
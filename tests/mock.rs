@@ -0,0 +1,1010 @@
+//! Tests of the transfer engine using `MockHardware`.
+#![cfg(feature = "mock")]
+
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use async_spi::mock::{Expectation, MockHardware};
+use async_spi::{
+    Crc7, Crc8, Error, Operation, OperationError, SPIHandler, StableDeref, TransferError,
+};
+
+fn handler() -> &'static SPIHandler<MockHardware> {
+    Box::leak(Box::new(SPIHandler::new()))
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Poll the future once, expecting it to be pending.
+fn poll<F: Future>(future: Pin<&mut F>) {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let pending = future.poll(&mut Context::from_waker(&waker));
+    assert!(matches!(pending, Poll::Pending));
+}
+
+/// Poll the future once, expecting it to be ready.
+fn ready<F: Future>(future: Pin<&mut F>) -> F::Output {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    match future.poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(x) => x,
+        Poll::Pending => panic!("The future is still pending."),
+    }
+}
+
+#[test]
+fn drop_aborts_transfer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 5),
+        Expectation::transfer(2, 0),
+        Expectation::transfer(3, 6),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    {
+        let mut xs = [1, 2];
+        let mut transfer = Box::pin(spi.transmit(&mut xs));
+        poll(transfer.as_mut());
+        // NOTE(unsafe): This simulates the interrupt handler, which does not run concurrently
+        // with the future.
+        unsafe { handler.handle_interrupt() };
+        poll(transfer.as_mut());
+        // The second frame is dropped while it is in progress.
+    }
+    assert_eq!(hw.aborts(), 1);
+    let mut xs = [3];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [6]);
+    hw.done();
+}
+
+#[test]
+fn leak_aborts_transfer_before_next() {
+    let handler = handler();
+    let hw = MockHardware::new(&[Expectation::transfer(1, 0), Expectation::transfer(2, 7)]);
+    let mut spi = handler.init(hw.clone());
+    let mut transfer = Box::pin(spi.write(&[1, 1]));
+    poll(transfer.as_mut());
+    core::mem::forget(transfer);
+    assert_eq!(hw.aborts(), 0);
+    let mut xs = [2];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(hw.aborts(), 1);
+    assert_eq!(xs, [7]);
+    hw.done();
+}
+
+#[test]
+fn timeout_aborts_stalled_transfer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        Expectation::stall(2),
+        Expectation::transfer(3, 4),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut delay = hw.delay();
+    // Five seconds is longer than a u32 of nanoseconds can hold.
+    let result = hw.block_on(
+        handler,
+        spi.write_with_timeout(&[1, 2], &mut delay, 5_000_000),
+    );
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::Timeout,
+            transferred: 1
+        })
+    ));
+    assert_eq!(hw.now(), 5_000_000_000);
+    assert_eq!(hw.aborts(), 1);
+    let mut xs = [3];
+    hw.block_on(
+        handler,
+        spi.transmit_with_timeout(&mut xs, &mut delay, 1_000),
+    )
+    .unwrap();
+    assert_eq!(xs, [4]);
+    assert_eq!(hw.now(), 5_000_000_000);
+    hw.done();
+}
+
+#[test]
+fn release_restores_frame_size() {
+    let handler = handler();
+    let hw = MockHardware::new(&[Expectation::transfer(1, 2)]);
+    let spi = handler.init(hw.clone()).with_frame_size::<u16>(12);
+    assert_eq!(hw.frame_size(), 12);
+    let hw = spi.release();
+    assert_eq!(hw.frame_size(), 8);
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [1];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [2]);
+    hw.done();
+}
+
+#[test]
+fn try_init_rejects_second_spi() {
+    let handler = handler();
+    let hw = MockHardware::new(&[Expectation::transfer(1, 2)]);
+    let spi = handler.init(hw.clone());
+    // The hardware is given back while the first SPI is still live.
+    let other = match handler.try_init(MockHardware::new(&[])) {
+        Err(other) => other,
+        Ok(_) => panic!("try_init succeeded while a SPI exists."),
+    };
+    let hw = spi.release();
+    let mut spi = match handler.try_init(hw.clone()) {
+        Ok(spi) => spi,
+        Err(_) => panic!("try_init failed after release."),
+    };
+    let mut xs = [1];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [2]);
+    hw.done();
+    other.done();
+}
+
+/// An owned buffer that records when it is dropped, with `N` words of padding to make it larger.
+struct Tracked<const N: usize> {
+    words: &'static mut [u8; 2],
+    dropped: Arc<AtomicBool>,
+    _padding: [usize; N],
+}
+
+impl<const N: usize> Tracked<N> {
+    fn new(words: &'static mut [u8; 2], dropped: &Arc<AtomicBool>) -> Self {
+        Self {
+            words,
+            dropped: dropped.clone(),
+            _padding: [0; N],
+        }
+    }
+}
+
+impl<const N: usize> Deref for Tracked<N> {
+    type Target = [u8; 2];
+
+    fn deref(&self) -> &[u8; 2] {
+        self.words
+    }
+}
+
+impl<const N: usize> DerefMut for Tracked<N> {
+    fn deref_mut(&mut self) -> &mut [u8; 2] {
+        self.words
+    }
+}
+
+unsafe impl<const N: usize> StableDeref for Tracked<N> {}
+
+impl<const N: usize> Drop for Tracked<N> {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn drop_owned_keeps_buffer_until_done() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 5),
+        Expectation::transfer(2, 6),
+        Expectation::transfer(3, 7),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let dropped = Arc::new(AtomicBool::new(false));
+    let words = Box::leak(Box::new([1, 2]));
+    let read: *const [u8; 2] = words;
+    {
+        let mut transfer = Box::pin(spi.transfer_owned(Tracked::<0>::new(words, &dropped)));
+        poll(transfer.as_mut());
+        // NOTE(unsafe): See `drop_aborts_transfer`.
+        unsafe { handler.handle_interrupt() };
+        poll(transfer.as_mut());
+    }
+    // The handler keeps the buffer while the second frame is in progress.
+    assert!(!dropped.load(Ordering::Relaxed));
+    let ys: &'static mut [u8; 1] = Box::leak(Box::new([3]));
+    let (ys, result) = hw.block_on(handler, spi.transfer_owned(ys));
+    result.unwrap();
+    assert!(dropped.load(Ordering::Relaxed));
+    assert_eq!(hw.aborts(), 0);
+    // NOTE(unsafe): The words were leaked, and the buffer that borrowed them has been dropped.
+    assert_eq!(unsafe { *read }, [5, 6]);
+    assert_eq!(*ys, [7]);
+    hw.done();
+}
+
+#[test]
+fn drop_large_owned_aborts_before_buffer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 5),
+        Expectation::stall(2),
+        Expectation::transfer(3, 6),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let dropped = Arc::new(AtomicBool::new(false));
+    let xs = Tracked::<4>::new(Box::leak(Box::new([1, 2])), &dropped);
+    {
+        let mut transfer = Box::pin(spi.transfer_owned(xs));
+        poll(transfer.as_mut());
+        // NOTE(unsafe): See `drop_aborts_transfer`.
+        unsafe { handler.handle_interrupt() };
+        poll(transfer.as_mut());
+    }
+    assert_eq!(hw.aborts(), 1);
+    assert!(dropped.load(Ordering::Relaxed));
+    let ys: &'static mut [u8; 1] = Box::leak(Box::new([3]));
+    let (ys, result) = hw.block_on(handler, spi.transfer_owned(ys));
+    result.unwrap();
+    assert_eq!(*ys, [6]);
+    hw.done();
+}
+
+#[test]
+fn transaction_reports_failed_operation() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        Expectation::transfer(2, 0),
+        Expectation::error(3, Error::Overrun),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [0; 2];
+    let mut ops = [
+        Operation::Write(&[1]),
+        Operation::DelayNs(500),
+        Operation::Write(&[2, 3]),
+        Operation::Read(&mut xs),
+    ];
+    let result = hw.block_on(handler, spi.transaction(&mut ops, &mut hw.delay()));
+    assert!(matches!(
+        result,
+        Err(OperationError {
+            index: 2,
+            error: Error::Overrun
+        })
+    ));
+    assert_eq!(hw.now(), 500);
+    hw.done();
+}
+
+/// A chip select pin that records its level.
+#[cfg(feature = "async-hal")]
+struct ChipSelect(Arc<AtomicBool>);
+
+#[cfg(feature = "async-hal")]
+impl embedded_hal::digital::ErrorType for ChipSelect {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(feature = "async-hal")]
+impl embedded_hal::digital::OutputPin for ChipSelect {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async-hal")]
+#[test]
+fn spi_bus_moves_words() {
+    use embedded_hal::spi::{Error as _, ErrorKind};
+    use embedded_hal_async::spi::SpiBus;
+
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::transfer(0, 6),
+        Expectation::transfer(3, 7),
+        Expectation::error(9, Error::Overrun),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut read = [0];
+    hw.block_on(handler, SpiBus::transfer(&mut spi, &mut read, &[1, 2]))
+        .unwrap();
+    assert_eq!(read, [4]);
+    hw.block_on(handler, SpiBus::read(&mut spi, &mut read))
+        .unwrap();
+    assert_eq!(read, [6]);
+    let mut xs = [3];
+    hw.block_on(handler, SpiBus::transfer_in_place(&mut spi, &mut xs))
+        .unwrap();
+    assert_eq!(xs, [7]);
+    let result = hw.block_on(handler, SpiBus::write(&mut spi, &[9]));
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::Overrun);
+    hw.done();
+}
+
+#[cfg(feature = "async-hal")]
+#[test]
+fn device_delays_on_simulated_clock() {
+    use async_spi::SPIDevice;
+    use embedded_hal::spi::Operation;
+    use embedded_hal_async::spi::SpiDevice;
+
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        Expectation::transfer(0, 7),
+        Expectation::transfer(0, 8),
+    ]);
+    let high = Arc::new(AtomicBool::new(false));
+    let spi = handler.init(hw.clone());
+    let mut device = SPIDevice::new(spi, ChipSelect(high.clone()), hw.delay()).unwrap();
+    let mut xs = [0; 2];
+    let mut ops = [
+        Operation::Write(&[1]),
+        Operation::DelayNs(2_000),
+        Operation::Read(&mut xs),
+    ];
+    hw.block_on(handler, device.transaction(&mut ops)).unwrap();
+    assert_eq!(xs, [7, 8]);
+    assert_eq!(hw.now(), 2_000);
+    assert!(high.load(Ordering::Relaxed));
+    hw.done();
+}
+
+#[cfg(feature = "async-hal")]
+#[test]
+fn shared_bus_reconfigures_on_change() {
+    use async_spi::{Config, SharedBus, MODE_0, MODE_3};
+    use embedded_hal_async::spi::SpiDevice;
+
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        Expectation::transfer(2, 0),
+        Expectation::transfer(3, 0),
+        Expectation::transfer(4, 0),
+    ]);
+    let bus = SharedBus::<_, 2>::new(handler.init(hw.clone()));
+    let slow = Config::new(MODE_0, 1_000_000);
+    let fast = Config::new(MODE_3, 8_000_000);
+    let cs = || ChipSelect(Arc::new(AtomicBool::new(false)));
+    let mut a = bus.device(cs(), hw.delay(), slow).unwrap();
+    let mut b = bus.device(cs(), hw.delay(), fast).unwrap();
+    hw.block_on(handler, a.write(&[1])).unwrap();
+    assert_eq!(hw.configurations(), 1);
+    assert_eq!(hw.config(), Some(slow));
+    // The configuration is already applied when the same device runs again.
+    hw.block_on(handler, a.write(&[2])).unwrap();
+    assert_eq!(hw.configurations(), 1);
+    hw.block_on(handler, b.write(&[3])).unwrap();
+    assert_eq!(hw.configurations(), 2);
+    assert_eq!(hw.config(), Some(fast));
+    hw.block_on(handler, a.write(&[4])).unwrap();
+    assert_eq!(hw.configurations(), 3);
+    assert_eq!(hw.config(), Some(slow));
+    hw.done();
+}
+
+#[cfg(feature = "async-hal")]
+#[test]
+fn shared_bus_fills_per_device() {
+    use async_spi::{Config, SharedBus, MODE_0};
+    use embedded_hal_async::spi::SpiDevice;
+
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(0xff, 1),
+        Expectation::transfer(0, 2),
+        Expectation::transfer(0xff, 3),
+    ]);
+    let bus = SharedBus::<_, 2>::new(handler.init(hw.clone()));
+    let config = Config::new(MODE_0, 1_000_000);
+    let cs = || ChipSelect(Arc::new(AtomicBool::new(false)));
+    let mut a = bus.device(cs(), hw.delay(), config).unwrap();
+    let mut b = bus.device(cs(), hw.delay(), config).unwrap();
+    a.set_fill(0xff);
+    let mut xs = [0];
+    hw.block_on(handler, a.read(&mut xs)).unwrap();
+    assert_eq!(xs, [1]);
+    hw.block_on(handler, b.read(&mut xs)).unwrap();
+    assert_eq!(xs, [2]);
+    hw.block_on(handler, a.read(&mut xs)).unwrap();
+    assert_eq!(xs, [3]);
+    hw.done();
+}
+
+#[cfg(feature = "async-hal")]
+#[test]
+fn shared_bus_rejects_unsupported_config() {
+    use async_spi::{Config, DeviceError, FrameFormat, SharedBus, MODE_0, MODE_1};
+    use embedded_hal_async::spi::SpiDevice;
+
+    let handler = handler();
+    let hw = MockHardware::new(&[Expectation::transfer(2, 0)]);
+    let bus = SharedBus::<_, 2>::new(handler.init(hw.clone()));
+    let pulsed = Config {
+        frame_format: FrameFormat::MotorolaPulsed,
+        ..Config::new(MODE_1, 1_000_000)
+    };
+    let high = Arc::new(AtomicBool::new(false));
+    let mut a = bus
+        .device(ChipSelect(high.clone()), hw.delay(), pulsed)
+        .unwrap();
+    let cs = ChipSelect(Arc::new(AtomicBool::new(false)));
+    let mut b = bus
+        .device(cs, hw.delay(), Config::new(MODE_0, 1_000_000))
+        .unwrap();
+    let result = hw.block_on(handler, a.write(&[1]));
+    assert!(matches!(result, Err(DeviceError::SPI(Error::Unsupported))));
+    // The device was never selected, and the bus can still be used by the other device.
+    assert!(high.load(Ordering::Relaxed));
+    assert_eq!(hw.configurations(), 0);
+    hw.block_on(handler, b.write(&[2])).unwrap();
+    assert_eq!(hw.configurations(), 1);
+    hw.done();
+}
+
+#[cfg(feature = "async-hal")]
+#[test]
+fn shared_bus_takes_turns() {
+    use async_spi::{Config, SharedBus, MODE_0};
+    use embedded_hal_async::spi::SpiDevice;
+
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        Expectation::transfer(3, 0),
+        Expectation::transfer(4, 0),
+    ]);
+    let bus = SharedBus::<_, 3>::new(handler.init(hw.clone()));
+    let config = Config::new(MODE_0, 1_000_000);
+    let cs = || ChipSelect(Arc::new(AtomicBool::new(false)));
+    let mut a = bus.device(cs(), hw.delay(), config).unwrap();
+    let mut b = bus.device(cs(), hw.delay(), config).unwrap();
+    let mut c = bus.device(cs(), hw.delay(), config).unwrap();
+    let mut first = Box::pin(a.write(&[1]));
+    poll(first.as_mut());
+    let mut second = Box::pin(b.write(&[2]));
+    poll(second.as_mut());
+    let mut third = Box::pin(c.write(&[3]));
+    poll(third.as_mut());
+    // NOTE(unsafe): See `drop_aborts_transfer`.
+    unsafe { handler.handle_interrupt() };
+    ready(first.as_mut()).unwrap();
+    drop(first);
+    // The bus goes round: `a` waits for the others before it gets another turn.
+    let mut fourth = Box::pin(a.write(&[4]));
+    poll(fourth.as_mut());
+    // `b` is dropped after the bus was handed to it, and passes it on to `c`.
+    drop(second);
+    poll(fourth.as_mut());
+    poll(third.as_mut());
+    // NOTE(unsafe): See above.
+    unsafe { handler.handle_interrupt() };
+    ready(third.as_mut()).unwrap();
+    poll(fourth.as_mut());
+    // NOTE(unsafe): See above.
+    unsafe { handler.handle_interrupt() };
+    ready(fourth.as_mut()).unwrap();
+    hw.done();
+}
+
+#[test]
+fn error_recovers_hardware() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::error(1, Error::ModeFault),
+        Expectation::transfer(2, 3),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let result = hw.block_on(handler, spi.write(&[1]));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::ModeFault,
+            ..
+        })
+    ));
+    assert_eq!(hw.recoveries(), 1);
+    let mut xs = [2];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [3]);
+    assert_eq!(hw.recoveries(), 1);
+    hw.done();
+}
+
+#[test]
+fn error_reports_frames_transferred() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::error(3, Error::Overrun),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [1, 2, 3];
+    let result = hw.block_on(handler, spi.transmit(&mut xs));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::Overrun,
+            transferred: 2
+        })
+    ));
+    assert_eq!(spi.transferred(), 2);
+    assert_eq!(xs[..2], [4, 5]);
+    hw.done();
+}
+
+#[test]
+fn transfer_pads_shorter_buffer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::transfer(3, 6),
+        Expectation::transfer(7, 8),
+        Expectation::transfer(0, 9),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    // The words read beyond the read buffer are discarded.
+    let mut read = [0];
+    hw.block_on(handler, spi.transfer(&mut read, &[1, 2, 3]))
+        .unwrap();
+    assert_eq!(read, [4]);
+    // The fill word is written beyond the write buffer.
+    let mut read = [0; 2];
+    hw.block_on(handler, spi.transfer(&mut read, &[7])).unwrap();
+    assert_eq!(read, [8, 9]);
+    hw.done();
+}
+
+#[test]
+fn read_writes_fill_word() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(0, 1),
+        Expectation::transfer(0xff, 2),
+        Expectation::transfer(0xff, 3),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [0];
+    hw.block_on(handler, spi.read(&mut xs)).unwrap();
+    assert_eq!(xs, [1]);
+    spi.set_fill(0xff);
+    let mut xs = [0; 2];
+    hw.block_on(handler, spi.read(&mut xs)).unwrap();
+    assert_eq!(xs, [2, 3]);
+    hw.done();
+}
+
+#[test]
+fn wide_frames_move_u16_words() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(0x123, 0xabc),
+        Expectation::transfer(0xfff, 0x001),
+    ]);
+    let mut spi = handler.init(hw.clone()).with_frame_size::<u16>(12);
+    assert_eq!(hw.frame_size(), 12);
+    let mut xs = [0x123, 0xfff];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [0xabc, 0x001]);
+    hw.done();
+}
+
+#[test]
+fn fifo_chains_operations() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        Expectation::transfer(2, 0),
+        Expectation::transfer(3, 0),
+        Expectation::transfer(0, 4),
+        Expectation::transfer(0, 5),
+        Expectation::transfer(7, 6),
+        Expectation::transfer(8, 9),
+    ])
+    .with_fifo_depth(4)
+    .with_crc(2);
+    let mut spi = handler.init(hw.clone());
+    let mut a = [0; 2];
+    let mut b = [0];
+    let mut ops = [
+        Operation::Write(&[1, 2, 3]),
+        Operation::Read(&mut a),
+        Operation::Transfer(&mut b, &[7, 8]),
+    ];
+    hw.block_on(handler, spi.transaction(&mut ops, &mut hw.delay()))
+        .unwrap();
+    assert_eq!(a, [4, 5]);
+    assert_eq!(b, [6]);
+    // The checksum is sent right after the last word, and its frames are not counted.
+    assert_eq!(hw.checksums(), [7]);
+    assert_eq!(spi.transferred(), 7);
+    hw.done();
+}
+
+#[test]
+fn bad_crc_recovers_hardware() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::transfer(3, 6),
+    ])
+    .with_crc(1);
+    let mut spi = handler.init(hw.clone());
+    hw.fail_crc();
+    let mut xs = [1, 2];
+    let result = hw.block_on(handler, spi.transmit(&mut xs));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::BadChecksum,
+            transferred: 2
+        })
+    ));
+    assert_eq!(xs, [4, 5]);
+    assert_eq!(hw.recoveries(), 1);
+    let mut xs = [3];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [6]);
+    assert_eq!(hw.recoveries(), 1);
+    hw.done();
+}
+
+#[test]
+fn fifo_pads_shorter_buffer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::transfer(3, 6),
+        Expectation::transfer(7, 8),
+        Expectation::transfer(0, 9),
+        Expectation::transfer(0, 10),
+    ])
+    .with_fifo_depth(4);
+    let mut spi = handler.init(hw.clone());
+    let mut read = [0];
+    hw.block_on(handler, spi.transfer(&mut read, &[1, 2, 3]))
+        .unwrap();
+    assert_eq!(read, [4]);
+    let mut read = [0; 3];
+    hw.block_on(handler, spi.transfer(&mut read, &[7])).unwrap();
+    assert_eq!(read, [8, 9, 10]);
+    hw.done();
+}
+
+#[test]
+fn fifo_error_discards_burst() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 5),
+        Expectation::error(2, Error::Overrun),
+        Expectation::transfer(3, 0),
+        Expectation::transfer(4, 0),
+        Expectation::transfer(6, 7),
+    ])
+    .with_fifo_depth(4);
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [1, 2, 3, 4, 5];
+    let result = hw.block_on(handler, spi.transmit(&mut xs));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::Overrun,
+            transferred: 1
+        })
+    ));
+    assert_eq!(xs, [5, 2, 3, 4, 5]);
+    assert_eq!(hw.recoveries(), 1);
+    // The frames still queued were discarded by the recovery.
+    let mut xs = [6];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [7]);
+    hw.done();
+}
+
+#[test]
+fn fifo_ignores_interrupt_without_frames() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::stall(3),
+    ])
+    .with_fifo_depth(4);
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [1, 2, 3];
+    {
+        let mut transfer = Box::pin(spi.transmit(&mut xs));
+        poll(transfer.as_mut());
+        // NOTE(unsafe): See `drop_aborts_transfer`.
+        unsafe { handler.handle_interrupt() };
+        poll(transfer.as_mut());
+        // The frames that arrived were read by the previous call, which triggered the interrupt
+        // again.
+        // NOTE(unsafe): See above.
+        unsafe { handler.handle_interrupt() };
+        poll(transfer.as_mut());
+    }
+    assert_eq!(xs, [4, 5, 3]);
+    assert_eq!(hw.aborts(), 1);
+    hw.done();
+}
+
+#[test]
+fn dma_pads_shorter_buffer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::transfer(3, 6),
+        Expectation::transfer(7, 8),
+        Expectation::transfer(0xff, 9),
+    ])
+    .with_dma();
+    let mut spi = handler.init(hw.clone());
+    spi.set_fill(0xff);
+    // A block that reads and writes, followed by one that only writes.
+    let mut read = [0];
+    hw.block_on(handler, spi.transfer(&mut read, &[1, 2, 3]))
+        .unwrap();
+    assert_eq!(read, [4]);
+    assert_eq!(hw.blocks(), 2);
+    // A block that reads and writes, followed by one that writes the fill word.
+    let mut read = [0; 2];
+    hw.block_on(handler, spi.transfer(&mut read, &[7])).unwrap();
+    assert_eq!(read, [8, 9]);
+    assert_eq!(hw.blocks(), 4);
+    hw.done();
+}
+
+#[test]
+fn dma_error_reports_completed_blocks() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        Expectation::error(3, Error::Dma),
+        Expectation::transfer(6, 7),
+    ])
+    .with_dma();
+    let mut spi = handler.init(hw.clone());
+    let mut read = [0; 2];
+    let result = hw.block_on(handler, spi.transfer(&mut read, &[1, 2, 3]));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::Dma,
+            transferred: 2
+        })
+    ));
+    assert_eq!(read, [4, 5]);
+    assert_eq!(hw.recoveries(), 1);
+    let mut xs = [6];
+    hw.block_on(handler, spi.transmit(&mut xs)).unwrap();
+    assert_eq!(xs, [7]);
+    hw.done();
+}
+
+#[test]
+fn respond_completes_on_deselect() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 4),
+        Expectation::transfer(2, 5),
+        // The master stops clocking after two frames, while the fill word waits to be sent.
+        Expectation::stall(0),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut rx = [0; 3];
+    {
+        let mut respond = Box::pin(spi.respond(&[1, 2], &mut rx));
+        poll(respond.as_mut());
+        // NOTE(unsafe): This simulates the interrupt handlers, which do not run concurrently with
+        // the future.
+        unsafe {
+            handler.handle_interrupt();
+            handler.handle_interrupt();
+        }
+        poll(respond.as_mut());
+        // NOTE(unsafe): See above.
+        unsafe { handler.handle_deselect() };
+        assert_eq!(ready(respond.as_mut()).unwrap(), 2);
+    }
+    assert_eq!(rx, [4, 5, 0]);
+    assert_eq!(hw.aborts(), 1);
+    hw.done();
+}
+
+#[test]
+fn failed_respond_discards_queued_frames() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::error(1, Error::Overrun),
+        // The master stops clocking while the second frame is queued for transmission.
+        Expectation::stall(2),
+    ])
+    .with_fifo_depth(2);
+    let mut spi = handler.init(hw.clone());
+    let mut rx = [0; 2];
+    let result = hw.block_on(handler, spi.respond(&[1, 2], &mut rx));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::Overrun,
+            transferred: 0
+        })
+    ));
+    assert_eq!(hw.recoveries(), 1);
+    // The next respond starts with its own frame rather than the one left queued.
+    hw.expect(&[Expectation::transfer(3, 5), Expectation::stall(0)]);
+    let mut rx = [0; 2];
+    {
+        let mut respond = Box::pin(spi.respond(&[3], &mut rx));
+        poll(respond.as_mut());
+        // NOTE(unsafe): See `respond_completes_on_deselect`.
+        unsafe {
+            handler.handle_interrupt();
+            handler.handle_deselect();
+        }
+        assert_eq!(ready(respond.as_mut()).unwrap(), 1);
+    }
+    assert_eq!(rx, [5, 0]);
+    hw.done();
+}
+
+#[test]
+fn interrupt_after_respond_is_ignored() {
+    let handler = handler();
+    let hw = MockHardware::new(&[Expectation::transfer(1, 4), Expectation::stall(0)]);
+    let mut spi = handler.init(hw.clone());
+    let mut rx = [0; 2];
+    {
+        let mut respond = Box::pin(spi.respond(&[1], &mut rx));
+        poll(respond.as_mut());
+        // NOTE(unsafe): See `respond_completes_on_deselect`.
+        unsafe {
+            handler.handle_interrupt();
+            handler.handle_deselect();
+        }
+        assert_eq!(ready(respond.as_mut()).unwrap(), 1);
+    }
+    // The master clocks frames while no `respond` is in progress.
+    // NOTE(unsafe): See above.
+    unsafe { handler.handle_interrupt() };
+    assert_eq!(rx, [4, 0]);
+    hw.expect(&[Expectation::transfer(2, 5), Expectation::stall(0)]);
+    let mut rx = [0; 1];
+    {
+        let mut respond = Box::pin(spi.respond(&[2], &mut rx));
+        poll(respond.as_mut());
+        // NOTE(unsafe): See above.
+        unsafe {
+            handler.handle_interrupt();
+            handler.handle_deselect();
+        }
+        assert_eq!(ready(respond.as_mut()).unwrap(), 1);
+    }
+    assert_eq!(rx, [5]);
+    hw.done();
+}
+
+#[test]
+fn half_duplex_turns_around_once() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(1, 0),
+        // The turnaround frame is only clocked before the first read.
+        Expectation::transfer(0, 9),
+        Expectation::transfer(0, 1),
+        Expectation::transfer(0, 2),
+        Expectation::transfer(0, 3),
+        Expectation::transfer(0, 4),
+    ])
+    .with_half_duplex();
+    let mut spi = handler.init(hw.clone());
+    spi.set_turnaround(1);
+    let mut a = [0; 2];
+    let mut b = [0; 2];
+    let mut ops = [
+        Operation::Write(&[1]),
+        Operation::Read(&mut a),
+        Operation::Read(&mut b),
+    ];
+    hw.block_on(handler, spi.transaction(&mut ops, &mut hw.delay()))
+        .unwrap();
+    assert_eq!(a, [1, 2]);
+    assert_eq!(b, [3, 4]);
+    assert!(!hw.output());
+    hw.done();
+}
+
+#[test]
+fn half_duplex_rejects_transfer() {
+    let handler = handler();
+    let hw = MockHardware::new(&[Expectation::transfer(1, 0)]).with_half_duplex();
+    let mut spi = handler.init(hw.clone());
+    let mut xs = [0];
+    let result = hw.block_on(handler, spi.transfer(&mut xs, &[1]));
+    assert!(matches!(
+        result,
+        Err(TransferError {
+            kind: Error::Unsupported,
+            transferred: 0
+        })
+    ));
+    // No transfer is in progress, so the interrupt handler ignores a spurious interrupt.
+    // NOTE(unsafe): See `drop_aborts_transfer`.
+    unsafe { handler.handle_interrupt() };
+    hw.block_on(handler, spi.write(&[1])).unwrap();
+    hw.done();
+}
+
+#[test]
+fn write_with_crc_appends_checksum() {
+    let handler = handler();
+    let hw = MockHardware::new(&[]);
+    // CMD0 of an SD card, whose last byte is the CRC7 followed by the end bit.
+    for &x in &[0xff, 0x40, 0, 0, 0, 0, 0x95] {
+        hw.expect(&[Expectation::transfer(x, 0)]);
+    }
+    let mut spi = handler.init(hw.clone());
+    let mut crc = Crc7::new();
+    let mut ops = [
+        Operation::Write(&[0xff]),
+        Operation::WriteWithCrc(&[0x40, 0, 0, 0, 0], &mut crc),
+    ];
+    hw.block_on(handler, spi.transaction(&mut ops, &mut hw.delay()))
+        .unwrap();
+    assert_eq!(spi.transferred(), 7);
+    hw.done();
+}
+
+#[test]
+fn read_with_crc_checks_checksum() {
+    let handler = handler();
+    let hw = MockHardware::new(&[
+        Expectation::transfer(0, 0xbe),
+        Expectation::transfer(0, 0xef),
+        Expectation::transfer(0, 0x92),
+        Expectation::transfer(0, 0xbe),
+        Expectation::transfer(0, 0xef),
+        Expectation::transfer(0, 0x93),
+    ]);
+    let mut spi = handler.init(hw.clone());
+    let mut crc = Crc8::new(0x31, 0xff);
+    let mut xs = [0; 2];
+    let mut ops = [Operation::ReadWithCrc(&mut xs, &mut crc)];
+    hw.block_on(handler, spi.transaction(&mut ops, &mut hw.delay()))
+        .unwrap();
+    assert_eq!(xs, [0xbe, 0xef]);
+    let mut ys = [0; 2];
+    let mut ops = [
+        Operation::Write(&[]),
+        Operation::ReadWithCrc(&mut ys, &mut crc),
+    ];
+    let result = hw.block_on(handler, spi.transaction(&mut ops, &mut hw.delay()));
+    assert!(matches!(
+        result,
+        Err(OperationError {
+            index: 1,
+            error: Error::BadChecksum
+        })
+    ));
+    assert_eq!(ys, [0xbe, 0xef]);
+    hw.done();
+}